@@ -0,0 +1,234 @@
+// benchmarks criterion : charges de lecture, d'écriture et mixtes sur 1 à 8 threads, clés
+// uniformes ou de Zipf, comparées à Mutex<HashMap> et RwLock<HashMap>, coût des doublements et
+// longueurs de sondage de LockedSlots et RobinHoodSlots selon le taux de remplissage
+//
+// cargo bench --bench chash -- --save-baseline main, puis --baseline main pour comparer.
+// chaque résultat est écrit en json dans target/criterion/<groupe>/<bench>/new/estimates.json
+use chash::{AtomicCHash, CHash, ConcurrentMultiset, Lock, LockedSlots, RobinHoodSlots, Slots};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, BuildHasherDefault};
+use std::sync::{Barrier, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// distinct keys, all of them are added before a workload is measured
+const KEYS: usize = 1 << 16;
+/// operations of one iteration, split between the threads
+const OPS: usize = 1 << 16;
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+
+/// xorshift64*, the streams are the same from one run to the next
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// uniform in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Keys {
+    Uniform,
+    /// key k is drawn with a probability proportional to 1/(k+1)
+    Zipf,
+}
+
+impl Keys {
+    fn name(self) -> &'static str {
+        match self {
+            Keys::Uniform => "uniform",
+            Keys::Zipf => "zipf",
+        }
+    }
+}
+
+/// cumulative distribution of the Zipf keys
+fn zipf_cdf() -> Vec<f64> {
+    let mut sum = 0.0;
+    let mut cdf: Vec<f64> = (0..KEYS)
+        .map(|k| {
+            sum += 1.0 / (k+1) as f64;
+            sum
+        })
+        .collect();
+    for p in cdf.iter_mut() {
+        *p /= sum;
+    }
+    cdf
+}
+
+/// the operations of one thread : true to add the key, false to look it up
+fn stream(keys: Keys, cdf: &[f64], writes: f64, seed: u64, len: usize) -> Vec<(bool, u64)> {
+    let mut rng = Rng(seed*2 + 1);
+    (0..len)
+        .map(|_| {
+            let write = rng.unit() < writes;
+            let key = match keys {
+                Keys::Uniform => rng.next() % KEYS as u64,
+                Keys::Zipf => {
+                    let u = rng.unit();
+                    cdf.partition_point(|&p| p < u).min(KEYS-1) as u64
+                },
+            };
+            (write, key)
+        })
+        .collect()
+}
+
+/// time taken by the threads to play their streams iters times, once they are all started
+fn run<M: ConcurrentMultiset<u64> + Sync>(map: &M, streams: &[Vec<(bool, u64)>], iters: u64) -> Duration {
+    let barrier = Barrier::new(streams.len() + 1);
+    thread::scope(|scope| {
+        for stream in streams {
+            let barrier = &barrier;
+            scope.spawn(move || {
+                barrier.wait();
+                let mut found = 0;
+                for _ in 0..iters {
+                    for &(write, key) in stream {
+                        if write {
+                            map.add(key);
+                        } else if map.contains(&key) {
+                            found += 1;
+                        }
+                    }
+                }
+                criterion::black_box(found);
+            });
+        }
+        barrier.wait();
+        let start = Instant::now();
+        // the scope joins the threads before returning
+        start
+    })
+    .elapsed()
+}
+
+fn filled<M: ConcurrentMultiset<u64>>(map: M) -> M {
+    for k in 0..KEYS as u64 {
+        map.add(k);
+    }
+    map
+}
+
+/// read-heavy, write-heavy and mixed workloads for every key distribution and number of threads
+fn workloads(c: &mut Criterion) {
+    let cdf = zipf_cdf();
+    let chash: CHash<u64> = filled(CHash::new());
+    let atomic: AtomicCHash<u64> = filled(CHash::default());
+    let mutex = filled(Mutex::new(HashMap::new()));
+    let rwlock = filled(RwLock::new(HashMap::new()));
+    for &(workload, writes) in &[("read_heavy", 0.1), ("write_heavy", 0.9), ("mixed", 0.5)] {
+        for &keys in &[Keys::Uniform, Keys::Zipf] {
+            let mut group = c.benchmark_group(format!("{}/{}", workload, keys.name()));
+            group.throughput(Throughput::Elements(OPS as u64));
+            for &threads in &THREADS {
+                let streams: Vec<_> = (0..threads)
+                    .map(|t| stream(keys, &cdf, writes, t as u64, OPS/threads))
+                    .collect();
+                group.bench_with_input(BenchmarkId::new("chash", threads), &streams, |b, s| {
+                    b.iter_custom(|iters| run(&chash, s, iters))
+                });
+                group.bench_with_input(BenchmarkId::new("atomic_chash", threads), &streams, |b, s| {
+                    b.iter_custom(|iters| run(&atomic, s, iters))
+                });
+                group.bench_with_input(BenchmarkId::new("mutex_hashmap", threads), &streams, |b, s| {
+                    b.iter_custom(|iters| run(&mutex, s, iters))
+                });
+                group.bench_with_input(BenchmarkId::new("rwlock_hashmap", threads), &streams, |b, s| {
+                    b.iter_custom(|iters| run(&rwlock, s, iters))
+                });
+            }
+            group.finish();
+        }
+    }
+}
+
+/// adding n values to a CHash that doubles from 4 Containers against one sized for them :
+/// the difference is the cost of the doublings
+fn double(c: &mut Criterion) {
+    let mut group = c.benchmark_group("double");
+    for &n in &[1usize << 10, 1 << 14, 1 << 18] {
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::new("growing", n), &n, |b, &n| {
+            b.iter_batched(CHash::<u64>::new, |ch| {
+                for k in 0..n as u64 {
+                    ch.add(k);
+                }
+                ch
+            }, BatchSize::LargeInput)
+        });
+        group.bench_with_input(BenchmarkId::new("presized", n), &n, |b, &n| {
+            b.iter_batched(|| CHash::<u64>::with_capacity(n), |ch| {
+                for k in 0..n as u64 {
+                    ch.add(k);
+                }
+                ch
+            }, BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+/// Containers of the tables whose probe lengths are measured
+const PROBED: usize = 1 << 16;
+
+/// home of a value in the tables of probes, hashed the same from one run to the next
+fn home(value: u64) -> usize {
+    BuildHasherDefault::<DefaultHasher>::default().hash_one(value) as usize % PROBED
+}
+
+/// Containers filled up to load, with the probe length of a lookup of each value, sorted
+fn probed<B: Slots<u64>>(load: f64) -> (B, Vec<usize>) {
+    let slots = B::with_len(PROBED);
+    for v in 0..(PROBED as f64 * load) as u64 {
+        slots.add(home(v), v, 1, usize::MAX, Lock::Wait).unwrap_or_else(|_| panic!("poisoned"));
+    }
+    let mut lengths = Vec::new();
+    slots.for_each_indexed(0..PROBED, Lock::Wait, |index,&v,_| lengths.push((index + PROBED - home(v)) % PROBED + 1))
+        .unwrap();
+    lengths.sort_unstable();
+    (slots, lengths)
+}
+
+/// looks every stored value up, after printing the distribution of their probe lengths
+fn lookups<B: Slots<u64>>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group(format!("probes/{}", name));
+    for &load in &[0.5, 0.75, 0.9] {
+        let (slots, lengths) = probed::<B>(load);
+        let mean = lengths.iter().sum::<usize>() as f64 / lengths.len() as f64;
+        let percentile = |p: f64| lengths[((lengths.len()-1) as f64 * p) as usize];
+        println!("{} at load {} : probe length mean {:.2} p50 {} p99 {} max {}",
+                 name, load, mean, percentile(0.5), percentile(0.99), lengths[lengths.len()-1]);
+        group.throughput(Throughput::Elements(lengths.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(load), &lengths.len(), |b, &n| {
+            b.iter(|| (0..n as u64).filter(|&v| slots.count(home(v), &v, Lock::Wait) == Ok(1)).count())
+        });
+    }
+    group.finish();
+}
+
+fn probes(c: &mut Criterion) {
+    lookups::<LockedSlots<u64>>(c, "linear");
+    lookups::<RobinHoodSlots<u64>>(c, "robin_hood");
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .sample_size(10)
+        .warm_up_time(Duration::from_millis(500))
+        .measurement_time(Duration::from_secs(2));
+    targets = workloads, double, probes
+}
+criterion_main!(benches);
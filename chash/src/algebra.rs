@@ -0,0 +1,212 @@
+// opérations ensemblistes entre multiensembles : chaque opération parcourt les Containers
+// des deux tables en parallèle, par tranches, et remplit un nouveau CHash concurrent
+use crate::{unwrap, CHash, Error, Lock, Slots, Tables};
+use std::cmp::{max, min};
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+
+/// smallest number of Containers handed to a thread
+const PARALLEL_CHUNK: usize = 4096;
+
+/// calls f on every (value, repetitions) of slots, splitting the Containers between threads.
+/// returns the first error met, the other values are still visited
+fn par_for_each<T, B, F>(slots: &B, f: F) -> Result<(), Error>
+    where B: Slots<T> + Sync, F: Fn(&T, usize) -> Result<(), Error> + Sync
+{
+    let failed = OnceLock::new();
+    let f = |v: &T, r| if let Err(e) = f(v,r) {
+        let _ = failed.set(e);
+    };
+    let len = slots.len();
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let chunk = max(len.div_ceil(threads), PARALLEL_CHUNK);
+    if chunk >= len {
+        slots.for_each(Lock::Wait, &f)?;
+    } else {
+        let f = &f;
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..len)
+                .step_by(chunk)
+                .map(|start| s.spawn(move || slots.for_each_in(start..min(start+chunk, len), Lock::Wait, f)))
+                .collect();
+            handles.into_iter().try_for_each(|h| h.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+        })?;
+    }
+    failed.into_inner().map_or(Ok(()), Err)
+}
+
+impl<T, S, B> CHash<T, S, B>
+    where T: Hash + Eq + Clone + Send + Sync, S: BuildHasher + Clone + Send + Sync, B: Slots<T> + Send + Sync
+{
+    /// each value with the largest of its two numbers of repetitions
+    pub fn union(&self, other: &Self) -> Self {
+        self.combine(other, true, max)
+    }
+
+    /// each value with both numbers of repetitions added
+    pub fn sum(&self, other: &Self) -> Self {
+        self.combine(other, true, usize::saturating_add)
+    }
+
+    /// each value with the smallest of its two numbers of repetitions
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.combine(other, false, min)
+    }
+
+    /// each value of self with the repetitions of other taken away, values left with none are dropped
+    pub fn difference(&self, other: &Self) -> Self {
+        self.combine(other, false, usize::saturating_sub)
+    }
+
+    /// whether no value has more repetitions in self than in other
+    pub fn is_subset(&self, other: &Self) -> bool {
+        unwrap(self.with_settled(other, |mine, theirs| {
+            let subset = AtomicBool::new(true);
+            par_for_each(&mine.current.slots, |v,r| {
+                if subset.load(Ordering::Relaxed) && r > theirs.current.count(v, Lock::Wait)? {
+                    subset.store(false, Ordering::Relaxed);
+                }
+                Ok(())
+            })?;
+            Ok(subset.into_inner())
+        }))
+    }
+
+    /// runs f with both CHash settled. the read locks are always taken in the same order
+    /// so that a.union(&b) and b.union(&a) can run together, and only once for a.union(&a)
+    ///
+    /// poisoned locks are reported whatever the PoisonPolicy, clearing them would need both write locks
+    fn with_settled<R, F>(&self, other: &Self, f: F) -> Result<R, Error>
+        where F: FnOnce(&Tables<T, S, B>, &Tables<T, S, B>) -> Result<R, Error>
+    {
+        if std::ptr::eq(self, other) {
+            let tables = self.settled(Lock::Wait)?;
+            f(&tables, &tables)
+        } else if (self as *const Self) < (other as *const Self) {
+            let mine = self.settled(Lock::Wait)?;
+            let theirs = other.settled(Lock::Wait)?;
+            f(&mine, &theirs)
+        } else {
+            let theirs = other.settled(Lock::Wait)?;
+            let mine = self.settled(Lock::Wait)?;
+            f(&mine, &theirs)
+        }
+    }
+
+    /// new CHash holding op(repetitions in self, repetitions in other) for every value of self,
+    /// and of other too when both_sides is set. values with no repetitions are left out
+    fn combine<F>(&self, other: &Self, both_sides: bool, op: F) -> Self
+        where F: Fn(usize, usize) -> usize + Sync
+    {
+        unwrap(self.with_settled(other, |mine, theirs| {
+            let mut capacity = self.used(mine);
+            if both_sides {
+                capacity += other.used(theirs);
+            }
+            let mut result = CHash::with_capacity_max_load_factor_and_hasher(capacity, self.max_load_factor, mine.current.hasher.clone());
+            result.set_max_repetitions(self.max_repetitions);
+            par_for_each(&mine.current.slots, |v,r| {
+                let repetitions = op(r, theirs.current.count(v, Lock::Wait)?);
+                if repetitions > 0 {
+                    result.add_n(v.clone(), repetitions);
+                }
+                Ok(())
+            })?;
+            if both_sides {
+                par_for_each(&theirs.current.slots, |v,r| {
+                    if mine.current.count(v, Lock::Wait)? == 0 {
+                        result.add_n(v.clone(), op(0, r));
+                    }
+                    Ok(())
+                })?;
+            }
+            Ok(result)
+        }))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{AtomicCHash, CHash};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    fn multiset(pairs: &[(&'static str, usize)]) -> CHash<&'static str> {
+        let ch = CHash::new();
+        for &(v,r) in pairs {
+            ch.add_n(v, r);
+        }
+        ch
+    }
+
+    fn counts<T: Clone + Eq + std::hash::Hash>(ch: &CHash<T>) -> HashMap<T, usize> {
+        ch.snapshot().into_iter().collect()
+    }
+
+    #[test]
+    fn operations() {
+        let a = multiset(&[("a", 3), ("b", 1), ("c", 2)]);
+        let b = multiset(&[("b", 4), ("c", 2), ("d", 1)]);
+        let expected = |pairs: &[(&'static str, usize)]| pairs.iter().cloned().collect::<HashMap<_, _>>();
+        assert_eq!(counts(&a.union(&b)), expected(&[("a", 3), ("b", 4), ("c", 2), ("d", 1)]));
+        assert_eq!(counts(&a.sum(&b)), expected(&[("a", 3), ("b", 5), ("c", 4), ("d", 1)]));
+        assert_eq!(counts(&a.intersection(&b)), expected(&[("b", 1), ("c", 2)]));
+        assert_eq!(counts(&a.difference(&b)), expected(&[("a", 3)]));
+        assert_eq!(a.sum(&b).total(), 13);
+        assert!(a.intersection(&b).is_subset(&a));
+        assert!(a.intersection(&b).is_subset(&b));
+        assert!(!a.is_subset(&b));
+        assert!(a.is_subset(&a.union(&b)));
+        assert!(CHash::new().is_subset(&a));
+        assert_eq!(counts(&a.union(&a)), counts(&a));
+        assert_eq!(a.difference(&a).total(), 0);
+    }
+
+    #[test]
+    fn large_operations() {
+        // large enough for the Containers to be split between threads
+        let a = AtomicCHash::<u64>::default();
+        let b = AtomicCHash::<u64>::default();
+        for i in 0..20_000 {
+            a.add_n(i, 2);
+            b.add_n(i+10_000, 3);
+        }
+        assert_eq!(a.union(&b).total(), 10_000*2 + 20_000*3);
+        assert_eq!(a.sum(&b).total(), 20_000*2 + 20_000*3);
+        let both = a.intersection(&b);
+        assert_eq!(both.total(), 10_000*2);
+        assert_eq!(both.count(&15_000), 2);
+        assert_eq!(both.count(&5_000), 0);
+        assert_eq!(a.difference(&b).total(), 10_000*2);
+        assert!(both.is_subset(&b));
+        assert!(!b.is_subset(&a));
+    }
+
+    #[test]
+    fn operations_threads() {
+        let a = Arc::new(CHash::new());
+        let b = Arc::new(CHash::new());
+        for i in 0..1000u32 {
+            a.add(i);
+            b.add(i);
+        }
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let (a, b) = if t%2 == 0 { (a.clone(), b.clone()) } else { (b.clone(), a.clone()) };
+                spawn(move || {
+                    for i in 0..100 {
+                        a.add(1000+i);
+                        assert!(a.intersection(&b).total() >= 1000);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(a.union(&b).total(), 1000 + 2*100);
+    }
+}
@@ -0,0 +1,48 @@
+// erreurs des méthodes try_ de CHash
+use std::fmt;
+use std::sync::TryLockError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// a thread panicked while holding a lock of the CHash, see PoisonPolicy
+    Poisoned,
+    /// the table would grow past the largest size that can be allocated
+    CapacityExceeded,
+    /// the lock needed is held by another thread and the call would have waited
+    WouldBlock,
+    /// the storage keeps this value for itself, see AtomicKey
+    ReservedValue,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Error::Poisoned => "a lock was poisoned by a panicking thread",
+            Error::CapacityExceeded => "the table cannot grow any further",
+            Error::WouldBlock => "the lock is held by another thread",
+            Error::ReservedValue => "the value is reserved by the storage and cannot be added",
+        })
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl<G> From<TryLockError<G>> for Error {
+    fn from(e: TryLockError<G>) -> Self {
+        match e {
+            TryLockError::Poisoned(_) => Error::Poisoned,
+            TryLockError::WouldBlock => Error::WouldBlock,
+        }
+    }
+}
+
+/// what CHash does when it finds a lock poisoned by a panicking thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoisonPolicy {
+    /// the try_ methods return Error::Poisoned, the others panic
+    #[default]
+    Propagate,
+    /// the poisoned Containers are cleared and the call goes on. the value a panicking thread
+    /// was working on is dropped and the total is counted again
+    Clear,
+}
@@ -0,0 +1,52 @@
+// garde d'une partie d'une table gardée avec la garde de lecture de la table qu'elle emprunte,
+// pour que les méthodes puissent renvoyer les deux ensemble
+use crate::sync::RwLockReadGuard;
+use crate::Error;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+
+/// dependent borrows the data read locked by table, Drop always drops it first
+pub(crate) struct Held<'a, X, G> {
+    dependent: ManuallyDrop<G>,
+    table: ManuallyDrop<RwLockReadGuard<'a, X>>,
+}
+
+impl<'a, X, G> Held<'a, X, G> {
+    /// f must keep the reference it is given only in what it returns
+    pub(crate) fn new<F>(table: RwLockReadGuard<'a, X>, f: F) -> Result<Option<Self>, Error>
+        where F: FnOnce(&'a X) -> Result<Option<G>, Error>
+    {
+        // SAFETY: X stays in the RwLock borrowed for 'a, moving the guard does not move it, and it
+        // cannot be written while the guard is alive. the guard is dropped after dependent, by Drop,
+        // or right here once f has returned nothing that borrows X
+        let locked: &'a X = unsafe { &*(&*table as *const X) };
+        Ok(f(locked)?.map(|dependent| Held {
+            dependent: ManuallyDrop::new(dependent),
+            table: ManuallyDrop::new(table),
+        }))
+    }
+}
+
+impl<X, G> Deref for Held<'_, X, G> {
+    type Target = G;
+
+    fn deref(&self) -> &G {
+        &self.dependent
+    }
+}
+
+impl<X, G> DerefMut for Held<'_, X, G> {
+    fn deref_mut(&mut self) -> &mut G {
+        &mut self.dependent
+    }
+}
+
+impl<X, G> Drop for Held<'_, X, G> {
+    fn drop(&mut self) {
+        // SAFETY: both fields are dropped once, here, and dependent first as it borrows what table locks
+        unsafe {
+            ManuallyDrop::drop(&mut self.dependent);
+            ManuallyDrop::drop(&mut self.table);
+        }
+    }
+}
@@ -1,338 +1,2367 @@
 // inspired by chashmap : https://docs.rs/chashmap/2.2.2/chashmap/
 
-// une suite pourrait etre de faire la même chose avec une valeur de répétitions max autorisées pour Container
-use std::hash::{Hash, Hasher, BuildHasher};
-use std::sync::RwLock;
+// le nombre de répétitions par Container peut être borné, voir CHash::with_max_repetitions
+use std::hash::{Hash, BuildHasher};
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::marker::PhantomData;
+use std::sync::{PoisonError, TryLockError};
 use std::collections::hash_map::RandomState;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::Ordering;
+use sync::{AtomicBool, AtomicUsize, RwLock, RwLockReadGuard};
+#[cfg(loom)]
+use sync::Poison;
+use std::time::{Duration, Instant};
 
+pub mod algebra;
+mod error;
+mod guard;
+pub mod map;
+pub mod multiset;
+pub mod sharded;
+pub mod sketch;
+pub mod slots;
+pub mod snapshot;
+mod sync;
+#[cfg(feature = "rayon")]
+mod rayon_impl;
+#[cfg(feature = "serde")]
+mod serde_impl;
+pub use error::{Error, PoisonPolicy};
+pub use map::CHashMap;
+pub use multiset::ConcurrentMultiset;
+pub use sharded::ShardedCHash;
+pub use sketch::CountMinSketch;
+use guard::Held;
+pub use slots::{Added, AtomicKey, AtomicSlots, Contention, Lock, LockedSlots, RobinHoodSlots, Slots};
 
-#[derive(Debug)]
-enum Container<T> {
-    Empty,
-    ElemRepeat(T,usize),
-}
-
-impl<T> Container<T> {
-    fn is_empty(&self) -> bool {
-        match self {
-            Container::Empty => true,
-            _ => false
-        }
-    }
-}
+#[cfg(feature = "rayon")]
+pub use rayon_impl::ParIter;
+use slots::Container;
 
 
 #[derive(Debug)]
-struct Table<T> {
-    containers: Vec<RwLock<Container<T>>>,
+struct Table<T, S = RandomState, B = LockedSlots<T>> {
+    slots: B,
     size: usize,
-    hasher: RandomState,
+    hasher: S,
+    _values: PhantomData<T>,
 }
 
-impl<T> Table<T> {
-    fn new(size: usize) -> Self {
-        let containers = (0..size)
-                .map(|_| RwLock::new(Container::Empty))
-                .collect();
-        let hasher = std::collections::hash_map::RandomState::new();
-        Table { containers, size, hasher }
+impl<T, S: BuildHasher, B: Slots<T>> Table<T, S, B> {
+    fn new(size: usize, hasher: S) -> Self {
+        let slots = B::with_len(size);
+        Table { slots, size, hasher, _values: PhantomData }
     }
 
     fn size(&self) -> usize {
         self.size
     }
 
-    /// returns true if a Container previously empty is used, false if the value was already there
-    fn add(&self, value: T, repeatitions: usize) -> bool
-        where T: Hash + PartialEq
+    /// T and its borrowed forms hash the same, see Borrow
+    fn home<Q: Hash + ?Sized>(&self, value: &Q) -> usize {
+        (self.hasher.hash_one(value) as usize) % self.size
+    }
+
+    /// returns true if a Container previously empty is used
+    fn add(&self, value: T, repeatitions: usize) -> Result<bool, (Error, T)>
+        where T: Hash
     {
-        let mut hasher = self.hasher.build_hasher();
-        value.hash(&mut hasher);
-        let mut hash = (hasher.finish() as usize) % self.size;
-        loop {
-            let mut chosen = self.containers[hash].write().unwrap();
-            match &*chosen {
-                Container::ElemRepeat(v,r) => {
-                    if v==&value {*chosen = Container::ElemRepeat(value,r+repeatitions); return false;}
-                    else {hash = (hash+1)%self.size;}
-                },
-                Container::Empty => {*chosen = Container::ElemRepeat(value, repeatitions); return true;},
-            }
-        }
+        self.add_bounded(value, repeatitions, usize::MAX, Lock::Wait).map(|added| added.claimed)
     }
 
-    /// the table must never be full otherwise infinite loop possible
-    fn contains(&self, value: T) -> bool 
-        where T: Hash + PartialEq
+    /// see Slots::add
+    fn add_bounded(&self, value: T, repeatitions: usize, max: usize, lock: Lock) -> Result<Added, (Error, T)>
+        where T: Hash
     {
-        let mut hasher = self.hasher.build_hasher();
-        value.hash(&mut hasher);
-        let mut hash = (hasher.finish() as usize) % self.size;
-        loop {
-            match &*self.containers[hash].read().unwrap() {
-                Container::Empty => return false,
-                Container::ElemRepeat(v,_) => {
-                    if v==&value {return true} else {hash = (hash+1)%self.size;}
-                }
-            }
-        }
+        let home = self.home(&value);
+        self.slots.add(home, value, repeatitions, max, lock)
     }
 
-    fn double(&mut self) 
-        where T: PartialEq + Hash
+    fn contains<Q>(&self, value: &Q, lock: Lock) -> Result<bool, Error>
+        where T: Borrow<Q>, Q: Hash + PartialEq + ?Sized
     {
-        let mut values_already_here = vec![];
-        for _ in 0..self.size {
-            let rwlock = self.containers.pop().unwrap();
-            let container = rwlock.into_inner().unwrap();
-            if let Container::ElemRepeat(v,r) = container {
-                values_already_here.push((v,r));
-            }
-        }
-        *self = Table::new(2*self.size);
-        for (v,r) in values_already_here {
-            self.add(v, r);
-        }
+        Ok(self.count(value, lock)? > 0)
+    }
+
+    fn count<Q>(&self, value: &Q, lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q>, Q: Hash + PartialEq + ?Sized
+    {
+        self.slots.count(self.home(value), value, lock)
+    }
+
+    /// returns the number of repetitions removed, 0 if the value was not there
+    fn remove<Q>(&self, value: &Q, lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q>, Q: Hash + PartialEq + ?Sized
+    {
+        self.slots.remove(self.home(value), value, lock)
+    }
+
+    /// returns the number of repetitions left, the value is dropped when it reaches 0
+    fn remove_one<Q>(&self, value: &Q, lock: Lock) -> Result<Option<usize>, Error>
+        where T: Borrow<Q>, Q: Hash + PartialEq + ?Sized
+    {
+        self.slots.remove_one(self.home(value), value, lock)
+    }
+
+    /// moves a value out of an old table into this one, giving it back if it cannot be added
+    fn receive(&self, value: T, repeatitions: usize) -> Result<(), (Error, T)>
+        where T: Hash
+    {
+        self.add(value, repeatitions).map(|_| ())
+    }
+
+    /// number of values, longest probe and sum of the probes to find each of them
+    fn probes(&self) -> Result<(usize, usize, usize), Error>
+        where T: Hash
+    {
+        let (mut values, mut longest, mut sum) = (0, 0, 0);
+        self.slots.for_each_indexed(0..self.size, Lock::Wait, |index,v,_| {
+            let probe = (index + self.size - self.home(v)) % self.size + 1;
+            values += 1;
+            longest = std::cmp::max(longest, probe);
+            sum += probe;
+        })?;
+        Ok((values, longest, sum))
     }
 
     fn iteratortable(self) -> IteratorTable<T> {
-        let mut containers = Vec::new();
-        for rwl_c in self.containers {
-            containers.push(rwl_c.into_inner().unwrap());
-        }
-        let current_index_containers = (0..self.size)
-            .into_iter()
-            .filter(|&i| !containers[i].is_empty())
-            .next()
-            .unwrap_or(self.size);
-        let remaining_numbers_rep : usize;
-        match containers[current_index_containers] {
-            Container::ElemRepeat(_, repeatitions) => remaining_numbers_rep = repeatitions,
-            Container::Empty => remaining_numbers_rep = 0,
+        IteratorTable::new(self.slots.into_pairs())
+    }
+}
+
+/// number of Containers of the old table moved by each call helping a resize
+const MIGRATION_CHUNK: usize = 16;
+
+/// the table in use and, during a resize, the old one whose values are being moved into it
+#[derive(Debug)]
+struct Tables<T, S, B> {
+    current: Table<T, S, B>,
+    old: Option<Table<T, S, B>>,
+    /// number of resizes so far, tells threads waiting to resize whether another one already did
+    resizes: usize,
+    /// contention of the tables dropped by resizes
+    retired: Contention,
+}
+
+impl<T, S: BuildHasher + Clone, B: Slots<T>> Tables<T, S, B> {
+    /// starts a resize, the values are then moved little by little by CHash::help
+    fn double(&mut self) {
+        self.resize(2*self.current.size);
+    }
+
+    /// starts a resize into an empty table of size Containers hashing with the same hasher
+    fn resize(&mut self, size: usize) {
+        let new = Table::new(size, self.current.hasher.clone());
+        self.old = Some(std::mem::replace(&mut self.current, new));
+        self.resizes += 1;
+    }
+
+    /// the old table, its contention is kept in retired
+    fn take_old(&mut self) -> Option<Table<T, S, B>> {
+        let old = self.old.take()?;
+        self.retired = self.retired + old.slots.contention();
+        Some(old)
+    }
+
+    /// moves what is left of the old table at once, only possible with exclusive access.
+    /// on error the values not moved yet stay in the old table
+    fn finish(&mut self) -> Result<(), Error>
+        where T: Hash
+    {
+        if let Some(old) = &self.old {
+            for index in 0..old.size {
+                old.slots.migrate(index, |v,r| self.current.receive(v,r))?;
+            }
         }
-        IteratorTable{ containers,
-                    current_index_containers,
-                    remaining_numbers_rep}
+        self.take_old();
+        Ok(())
     }
 }
 
+/// concurrent multiset, values are hashed with S and the storage of its Containers is chosen by B
+///
+/// resizing is incremental : a table twice bigger, or of the same size when tombstones rather than
+/// values filled it, is allocated and the values of the old one are moved by chunks of
+/// MIGRATION_CHUNK Containers during the following add and contains calls.
+/// before using the new table, each call also moves the probe chain of its value in the old one,
+/// so lookups see both tables until the resize is over
+///
+/// the table grows when its load factor would exceed max_load_factor, and always keeps at least two
+/// empty Containers so that probing ends. it only shrinks when asked to, see shrink_to_fit and
+/// set_min_load_factor
+///
+/// the try_ methods never wait for a lock held by another thread nor for a resize to finish, they
+/// return Error::WouldBlock instead. they also return an Error instead of panicking when a lock was
+/// poisoned by a panicking thread or the table cannot grow, see PoisonPolicy to clear the poisoned
+/// Containers instead
 #[derive(Debug)]
-pub struct CHash<T> {
-    table: RwLock<Table<T>>,
+pub struct CHash<T, S = RandomState, B = LockedSlots<T>> {
+    table: RwLock<Tables<T, S, B>>,
+    /// Containers of the current table that can still be used before resizing, the values still in
+    /// the old one counted as used. each add reserves one before probing
     remaining: AtomicUsize,
+    /// sum of the repetitions of every value
+    total: AtomicUsize,
+    /// number of values with at least one repetition, counted again when the table is rebuilt
+    distinct: AtomicUsize,
+    max_load_factor: f64,
+    /// the table shrinks when a removal leaves it less loaded than this, 0 never
+    min_load_factor: f64,
+    max_repetitions: usize,
+    /// next index of the old table to migrate
+    migration_next: AtomicUsize,
+    /// number of indexes of the old table already migrated
+    migration_done: AtomicUsize,
+    /// a chunk of the old table could not be migrated, only Tables::finish can end the resize
+    migration_stalled: AtomicBool,
+    poison_policy: PoisonPolicy,
 }
 
-impl<T> CHash<T> {
+/// CHash backed by lock free AtomicSlots, for integer-like values
+pub type AtomicCHash<T, S = RandomState> = CHash<T, S, AtomicSlots<T>>;
+
+/// CHash with Robin Hood probing, shorter probes at high load factors
+pub type RobinHoodCHash<T, S = RandomState> = CHash<T, S, RobinHoodSlots<T>>;
+
+const DEFAULT_MAX_LOAD_FACTOR: f64 = 0.75;
+
+/// first and longest sleeps of add_timeout between two attempts
+const MIN_BACKOFF: Duration = Duration::from_micros(10);
+const MAX_BACKOFF: Duration = Duration::from_millis(1);
+
+impl<T: PartialEq> CHash<T> {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// room for capacity values without resizing
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_max_load_factor(capacity, DEFAULT_MAX_LOAD_FACTOR)
+    }
+
+    /// max_load_factor must be in ]0, 1], the default is 0.75
+    pub fn with_max_load_factor(max_load_factor: f64) -> Self {
+        Self::with_capacity_and_max_load_factor(0, max_load_factor)
+    }
+
+    /// add saturates at max_repetitions repetitions per value, see set_max_repetitions
+    pub fn with_max_repetitions(max_repetitions: usize) -> Self {
+        let mut ch = Self::new();
+        ch.set_max_repetitions(max_repetitions);
+        ch
+    }
+}
+
+/// what add did with a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddOutcome {
+    /// the value was not there
+    Inserted,
+    /// the value was there, all the repetitions were added
+    Incremented,
+    /// the maximum number of repetitions was reached, some or all of the repetitions were refused
+    Saturated,
+}
+
+/// state of a CHash, see CHash::stats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// number of values with at least one repetition
+    pub distinct: usize,
+    /// sum of the repetitions of every value
+    pub total: usize,
+    /// number of Containers of the table
+    pub slots: usize,
+    /// distinct / slots
+    pub load_factor: f64,
+    /// Containers that hold no value but cannot be used, the tombstones left by removals.
+    /// they are only given back by the next resize, see shrink_to_fit
+    pub tombstones: usize,
+    /// most Containers visited to find a stored value, 1 when it is at its home
+    pub max_probe_length: usize,
+    pub mean_probe_length: f64,
+    pub resizes: usize,
+    /// lock acquisitions on Containers that had to wait, since the CHash was created
+    pub contention: Contention,
+}
+
+/// number of Containers of a table of the given size that can be used before it has to grow
+fn max_used(size: usize, max_load_factor: f64) -> usize {
+    std::cmp::min((size as f64 * max_load_factor) as usize, size - 2)
+}
+
+/// the infallible methods panic with the error, as the lock they could not take would
+fn unwrap<R>(result: Result<R, Error>) -> R {
+    result.unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// size of the smallest table with room for capacity values
+fn size_for(capacity: usize, max_load_factor: f64) -> usize {
+    let mut size = 4;
+    while max_used(size, max_load_factor) < capacity {
+        size *= 2;
+    }
+    size
+}
+
+impl<T, S: BuildHasher + Clone, B: Slots<T>> CHash<T, S, B> {
+    /// the hasher is also used by every table the CHash grows into
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_capacity_max_load_factor_and_hasher(0, DEFAULT_MAX_LOAD_FACTOR, hasher)
+    }
+
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        Self::with_capacity_max_load_factor_and_hasher(capacity, DEFAULT_MAX_LOAD_FACTOR, hasher)
+    }
+
+    pub fn with_capacity_and_max_load_factor(capacity: usize, max_load_factor: f64) -> Self
+        where S: Default
+    {
+        Self::with_capacity_max_load_factor_and_hasher(capacity, max_load_factor, S::default())
+    }
+
+    pub fn with_capacity_max_load_factor_and_hasher(capacity: usize, max_load_factor: f64, hasher: S) -> Self {
+        assert!(max_load_factor > 0.0 && max_load_factor <= 1.0, "max_load_factor must be in ]0, 1]");
+        Self::with_size(size_for(capacity, max_load_factor), max_load_factor, hasher)
+    }
+
+    /// size is the number of Containers of the table, a power of two
+    fn with_size(size: usize, max_load_factor: f64, hasher: S) -> Self {
         CHash {
-                table: RwLock::new(Table::new(4)),
-                remaining: AtomicUsize::new(4),}        
+                table: RwLock::new(Tables { current: Table::new(size, hasher), old: None, resizes: 0, retired: Contention::default() }),
+                remaining: AtomicUsize::new(max_used(size, max_load_factor)),
+                total: AtomicUsize::new(0),
+                distinct: AtomicUsize::new(0),
+                max_load_factor,
+                min_load_factor: 0.0,
+                max_repetitions: usize::MAX,
+                migration_next: AtomicUsize::new(0),
+                migration_done: AtomicUsize::new(0),
+                migration_stalled: AtomicBool::new(false),
+                poison_policy: PoisonPolicy::default(),}
+    }
+
+    pub fn max_load_factor(&self) -> f64 {
+        self.max_load_factor
+    }
+
+    pub fn max_repetitions(&self) -> usize {
+        self.max_repetitions
+    }
+
+    pub fn min_load_factor(&self) -> f64 {
+        self.min_load_factor
+    }
+
+    /// low-water mark : a removal that leaves less than min_load_factor*size values shrinks the table
+    /// to the smallest size holding them, see shrink_to_fit. min_load_factor must be below half the
+    /// max_load_factor so that a shrunk table does not grow back at once, 0 disables it (the default)
+    pub fn set_min_load_factor(&mut self, min_load_factor: f64) {
+        assert!(min_load_factor >= 0.0 && min_load_factor < self.max_load_factor/2.0,
+                "min_load_factor must be in [0, max_load_factor/2[");
+        self.min_load_factor = min_load_factor;
+    }
+
+    /// bounds the number of repetitions kept for each value, usize::MAX by default.
+    /// values already over it are left as they are
+    pub fn set_max_repetitions(&mut self, max_repetitions: usize) {
+        assert!(max_repetitions > 0, "max_repetitions must be positive");
+        self.max_repetitions = max_repetitions;
+    }
+
+    pub fn poison_policy(&self) -> PoisonPolicy {
+        self.poison_policy
+    }
+
+    /// what to do with the locks poisoned by a panicking thread, PoisonPolicy::Propagate by default
+    pub fn set_poison_policy(&mut self, poison_policy: PoisonPolicy) {
+        self.poison_policy = poison_policy;
     }
 
-    pub fn add(&self, value: T) 
-        where T: Hash + PartialEq
+    pub fn add(&self, value: T) -> AddOutcome
+        where T: Hash
     {
-        if self.remaining.load(Ordering::SeqCst) <= 2 {
-            self.bigger();
-        }
-        let table = self.table.read().unwrap();
-        if table.add(value,1) {
-            self.remaining.fetch_sub(1, Ordering::SeqCst);
-        }
+        self.add_n(value, 1)
     }
 
-    fn bigger(&self) 
-        where T: Hash + PartialEq
+    /// adds repetitions copies of value at once, as many as max_repetitions allows.
+    /// adding 0 repetitions changes nothing and reports Incremented
+    pub fn add_n(&self, value: T, repetitions: usize) -> AddOutcome
+        where T: Hash
     {
-        let mut table = self.table.write().unwrap();
-        let size = table.size;
-        table.double();
-        self.remaining.fetch_add(size, Ordering::SeqCst);
+        unwrap(self.add_until(value, repetitions, Lock::Wait, None))
     }
 
-    pub fn contains(&self, value: T) -> bool 
-        where T: Hash + PartialEq
+    pub fn try_add(&self, value: T) -> Result<AddOutcome, Error>
+        where T: Hash
     {
-        let table  = self.table.read().unwrap();
-        table.contains(value)
+        self.try_add_n(value, 1)
     }
 
-    pub fn size(&self) -> usize {
-        let table = self.table.read().unwrap();
-        table.size()
+    /// see add_n, the value is dropped when an error is returned
+    pub fn try_add_n(&self, value: T, repetitions: usize) -> Result<AddOutcome, Error>
+        where T: Hash
+    {
+        self.add_until(value, repetitions, Lock::Try, None)
     }
 
-    pub fn iteratortable(self) -> IteratorTable<T> {
-        let table = self.table.into_inner().unwrap();
-        table.iteratortable()
+    /// tries to add value until timeout is elapsed, returns Error::WouldBlock if the locks it
+    /// needs were held by other threads all along. a timeout too long to be represented waits like add
+    ///
+    /// it polls rather than waits on the locks : between two attempts it sleeps, MIN_BACKOFF at first
+    /// then twice longer each time up to MAX_BACKOFF, and never past the deadline
+    pub fn add_timeout(&self, value: T, timeout: Duration) -> Result<AddOutcome, Error>
+        where T: Hash
+    {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.add_until(value, 1, Lock::Try, Some(deadline)),
+            None => self.add_until(value, 1, Lock::Wait, None),
+        }
     }
-}
 
-pub struct IteratorTable<T> {
-    containers: Vec<Container<T>>,
-    current_index_containers: usize,
-    remaining_numbers_rep: usize,
-}
+    /// calls add_once again once the poisoned locks are cleared, if the policy allows it,
+    /// and while the locks are held by other threads until deadline, if any
+    fn add_until(&self, mut value: T, repetitions: usize, lock: Lock, deadline: Option<Instant>) -> Result<AddOutcome, Error>
+        where T: Hash
+    {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match self.add_once(value, repetitions, lock) {
+                Err((Error::Poisoned, v)) if self.poison_policy == PoisonPolicy::Clear => {
+                    self.recover(lock)?;
+                    value = v;
+                },
+                Err((Error::WouldBlock, v)) if deadline.is_some_and(|deadline| Instant::now() < deadline) => {
+                    let left = deadline.map_or(backoff, |deadline| deadline.saturating_duration_since(Instant::now()));
+                    std::thread::sleep(backoff.min(left));
+                    backoff = std::cmp::min(backoff*2, MAX_BACKOFF);
+                    value = v;
+                },
+                result => return result.map_err(|(e,_)| e),
+            }
+        }
+    }
 
-impl<T: Copy> Iterator for IteratorTable<T> {
-    type Item = T;
+    /// one attempt of add_n, the value is given back with the error
+    fn add_once(&self, value: T, repetitions: usize, lock: Lock) -> Result<AddOutcome, (Error, T)>
+        where T: Hash
+    {
+        if !B::accepts(&value) {
+            return Err((Error::ReservedValue, value));
+        }
+        if repetitions == 0 {
+            return Ok(AddOutcome::Incremented);
+        }
+        let tables = match self.reserve(lock) {
+            Ok(tables) => tables,
+            Err(e) => return Err((e, value)),
+        };
+        let (finished, added) = match self.help(&tables, Some(&value), lock) {
+            Ok(finished) => (finished, tables.current.add_bounded(value, repetitions, self.max_repetitions, lock)),
+            Err(e) => (false, Err((e, value))),
+        };
+        match &added {
+            Ok(added) => {
+                if added.inserted {
+                    self.distinct.fetch_add(1, Ordering::SeqCst);
+                }
+                if !added.claimed {
+                    // no empty Container was taken, the reserved one is given back
+                    self.remaining.fetch_add(1, Ordering::SeqCst);
+                }
+                self.total.fetch_add(added.repetitions, Ordering::SeqCst);
+            },
+            Err(_) => {self.remaining.fetch_add(1, Ordering::SeqCst);},
+        }
+        drop(tables);
+        if finished {
+            // a poisoned table lock is reported by the next call, the value was added
+            let _ = self.retire();
+        }
+        let added = added?;
+        Ok(if added.repetitions < repetitions {
+            AddOutcome::Saturated
+        } else if added.inserted {
+            AddOutcome::Inserted
+        } else {
+            AddOutcome::Incremented
+        })
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.current_index_containers >= self.containers.len() {
-            None
-        } else if self.remaining_numbers_rep>0{
-            self.remaining_numbers_rep -= 1;
-            match self.containers[self.current_index_containers] {
-                Container::ElemRepeat(value, _) => Some(value),
-                Container::Empty => None
+    /// read lock on the tables once a Container of the current one is reserved, growing it if needed.
+    /// the reservation is made under the read lock so that it cannot straddle a resize
+    fn reserve(&self, lock: Lock) -> Result<RwLockReadGuard<'_, Tables<T, S, B>>, Error>
+        where T: Hash
+    {
+        loop {
+            let tables = lock.read(&self.table)?;
+            if self.remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |r| r.checked_sub(1)).is_ok() {
+                return Ok(tables);
             }
+            let resizes = tables.resizes;
+            drop(tables);
+            self.bigger(resizes, lock)?;
+        }
+    }
+
+    /// allocates the new table, twice bigger unless tombstones took the room, no value is moved
+    /// here. nothing is done if the table was resized
+    /// since resizes was read, every thread that saw it full waits here but only the first one resizes
+    fn bigger(&self, resizes: usize, lock: Lock) -> Result<(), Error>
+        where T: Hash
+    {
+        let mut tables = lock.write(&self.table)?;
+        if tables.resizes != resizes {
+            return Ok(());
+        }
+        // every Container holds at least a T, the doubled table must fit in the address space
+        if tables.current.size > isize::MAX as usize / 2 / std::mem::size_of::<T>().max(1) {
+            return Err(Error::CapacityExceeded);
+        }
+        tables.finish()?;
+        self.migration_stalled.store(false, Ordering::SeqCst);
+        // only the values are moved, the tombstones that filled the table stay behind. it keeps
+        // its size while the values take at most half of the room, so that rebuilds stay amortized
+        let distinct = self.distinct.load(Ordering::SeqCst);
+        let size = tables.current.size;
+        if distinct <= max_used(size, self.max_load_factor) / 2 {
+            tables.resize(size);
         } else {
-            match (self.current_index_containers+1..self.containers.len()).into_iter().filter(|&i| !self.containers[i].is_empty()).next() {
-                Some(index) => {
-                    self.current_index_containers = index;
-                    match self.containers[self.current_index_containers] {
-                        Container::ElemRepeat(value,repeatitions) => {
-                            self.remaining_numbers_rep = repeatitions-1;
-                            Some(value)
-                        }
-                        Container::Empty => None
-                    }
-                }
-                None => {
-                    self.current_index_containers = self.containers.len();
-                    None
-                }
+            tables.double();
+        }
+        self.migration_next.store(0, Ordering::SeqCst);
+        self.migration_done.store(0, Ordering::SeqCst);
+        let allowed = max_used(tables.current.size, self.max_load_factor);
+        self.remaining.store(allowed.saturating_sub(distinct), Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// moves the probe chain of value and the next chunk of the old table, if a resize is going on.
+    /// returns true if the last chunk was moved by this call, the caller must then retire the old
+    /// table once its read lock is released. with Lock::Try only the probe chain is moved
+    fn help<Q>(&self, tables: &Tables<T, S, B>, value: Option<&Q>, lock: Lock) -> Result<bool, Error>
+        where T: Hash, Q: Hash + ?Sized
+    {
+        let old = match &tables.old {
+            Some(old) => old,
+            None => return Ok(false),
+        };
+        let current = &tables.current;
+        if let Some(value) = value {
+            old.slots.migrate_chain(old.home(value), lock, |v,r| current.receive(v,r))?;
+        }
+        if lock == Lock::Try {
+            // migrate waits for the Containers, a chunk claimed must be moved entirely
+            return Ok(false);
+        }
+        let start = self.migration_next.fetch_add(MIGRATION_CHUNK, Ordering::SeqCst);
+        if start >= old.size {
+            return Ok(false);
+        }
+        let end = std::cmp::min(start+MIGRATION_CHUNK, old.size);
+        for index in start..end {
+            if let Err(e) = old.slots.migrate(index, |v,r| current.receive(v,r)) {
+                // no other thread will claim this chunk again
+                self.migration_stalled.store(true, Ordering::SeqCst);
+                return Err(e);
             }
         }
+        Ok(self.migration_done.fetch_add(end-start, Ordering::SeqCst) + end-start == old.size)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Arc;
-    use std::thread::spawn;
-    use std::time::Instant;
-    use std::collections::HashMap;
-    #[test]
-    fn it_works() {
-        let table = Arc::new(Table::new(4));
-        let t1 = table.clone();
-        let handler1 = spawn(move || {
-            t1.add(0,1);
-            t1.add(1,1);
-        });
-        let t2 = table.clone();
-        let handler2 = spawn(move || {
-            t2.add(1,1);
-            t2.add(4,1);
-        });
-        handler1.join();
-        handler2.join();
-        assert!(table.contains(1));
-        assert!(table.contains(0));
-        assert!(table.contains(4));
-        assert!(!table.contains(2));
+    /// drops the old table if every chunk was migrated. a new resize may have started since the
+    /// last chunk was moved, the counters are then reset and the new old table is kept
+    fn retire(&self) -> Result<(), Error> {
+        let mut tables = self.table.write().map_err(|_| Error::Poisoned)?;
+        let done = match &tables.old {
+            Some(old) => self.migration_done.load(Ordering::SeqCst) >= old.size,
+            None => false,
+        };
+        if done {
+            tables.take_old();
+            self.recount(&tables);
+        }
+        Ok(())
     }
 
-    #[test]
-    fn iterator() {
-        let table = Table::new(4);
-        table.add(4,1);
-        table.add(0,1);
-        table.add(1,1);
-        table.add(0,1);
-        table.add(2,1);
-        let mut output = table.iteratortable().into_iter().collect::<Vec<_>>();
-        output.sort();
-        assert_eq!(vec![0,0,1,2,4],output);
+    /// counts the Containers of the current table in use again once the old table is gone, so that
+    /// only its values and tombstones are. needs the write lock, no reservation is then pending
+    fn recount(&self, tables: &Tables<T, S, B>) {
+        // on a poisoned Container the count kept is only too low
+        if let Ok(used) = tables.current.slots.used(Lock::Wait) {
+            let allowed = max_used(tables.current.size, self.max_load_factor);
+            self.remaining.store(allowed.saturating_sub(used), Ordering::SeqCst);
+        }
     }
 
-    #[test]
-    fn beaucoup() {
-        let table = Arc::new(Table::new(10_000));
-        let (t1,t2,t3) = (table.clone(), table.clone(), table.clone());
-        let start = Instant::now();
-        let h1 = spawn(move || {
-            for i in 0..4000 {
-                t1.add(i,1);
+    /// helps the resize in progress until the old table is gone, with Lock::Try a resize in
+    /// progress is not waited for
+    fn finish_resize(&self, lock: Lock) -> Result<(), Error>
+        where T: Hash
+    {
+        loop {
+            let tables = lock.read(&self.table)?;
+            let old_size = match &tables.old {
+                Some(_) if lock == Lock::Try => return Err(Error::WouldBlock),
+                Some(old) => old.size,
+                None => return Ok(()),
+            };
+            let mut finished = false;
+            while self.migration_next.load(Ordering::SeqCst) < old_size {
+                finished |= self.help::<T>(&tables, None, lock)?;
             }
-        });
-        let h2 = spawn(move || {
-            for i in 4000..7000 {
-                t2.add(i,1);
+            drop(tables);
+            if finished {
+                self.retire()?;
+            } else if self.migration_stalled.load(Ordering::SeqCst) {
+                return Err(Error::Poisoned);
+            } else {
+                // the last chunk is being moved by another thread
+                sync::yield_now();
             }
-        });
-        let h3 = spawn(move || {
-            for i in 7000..10000 {
-                t3.add(i,1);
+        }
+    }
+
+    /// runs f on the current table once the probe chain of value was moved out of the old one
+    fn on_current<Q, R, F>(&self, value: &Q, lock: Lock, f: F) -> Result<R, Error>
+        where T: Hash, Q: Hash + ?Sized, F: FnOnce(&Table<T, S, B>) -> Result<R, Error>
+    {
+        let tables = lock.read(&self.table)?;
+        let finished = self.help(&tables, Some(value), lock)?;
+        let result = f(&tables.current);
+        drop(tables);
+        if finished {
+            // a poisoned table lock is reported by the next call
+            let _ = self.retire();
+        }
+        result
+    }
+
+    /// runs f again once the poisoned locks are cleared, if the policy allows it
+    fn recovering<R, F>(&self, lock: Lock, mut f: F) -> Result<R, Error>
+        where T: Hash, F: FnMut() -> Result<R, Error>
+    {
+        loop {
+            match f() {
+                Err(Error::Poisoned) if self.poison_policy == PoisonPolicy::Clear => self.recover(lock)?,
+                result => return result,
             }
-        });
-        h1.join();
-        h2.join();
-        h3.join();
-        let duration = start.elapsed();
-        // utiliser cargo test -- --nocapture pour voir les print durant test
-        println!("Time elapsed for chash with 3 spawns : {:?}", duration);
-        assert!(table.contains(0));
-        let mut map = HashMap::new();
-        let start2 = Instant::now();
-        for i in 0..10_000 {
-            map.insert(i,i);
         }
-        let duration2 = start2.elapsed();
-        println!("Time elapsed for std hashmap : {:?}",duration2);
     }
 
-    #[test]
-    fn chash_test() {
-        let ch = CHash::new();
-        for i in 0..6 {
-            ch.add(i);
+    /// clears the poisoned locks, finishes the resize that may have been interrupted and
+    /// counts the total again, see PoisonPolicy::Clear
+    fn recover(&self, lock: Lock) -> Result<(), Error>
+        where T: Hash
+    {
+        let mut tables = match lock {
+            Lock::Wait => self.table.write().unwrap_or_else(PoisonError::into_inner),
+            Lock::Try => match self.table.try_write() {
+                Ok(tables) => tables,
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => return Err(Error::WouldBlock),
+            },
+        };
+        self.table.clear_poison();
+        tables.current.slots.clear_poisoned();
+        if let Some(old) = &mut tables.old {
+            old.slots.clear_poisoned();
         }
-        //let table = ch.table.into_inner().unwrap();
-        //println!("{:?}",table.iteratortable().into_iter().collect::<Vec<_>>());
-        assert!(ch.contains(0));
+        tables.finish()?;
+        self.migration_stalled.store(false, Ordering::SeqCst);
+        self.recount(&tables);
+        let (mut distinct, mut total) = (0, 0);
+        tables.current.slots.for_each(Lock::Wait, |_,r| {
+            distinct += 1;
+            total += r;
+        })?;
+        self.distinct.store(distinct, Ordering::SeqCst);
+        self.total.store(total, Ordering::SeqCst);
+        Ok(())
     }
 
-    #[test]
-    fn chash_threads_test() {
-        let ch = Arc::new(CHash::new());
-        let ch1 = ch.clone();
-        let ch2 = ch.clone();
+    /// value can be any borrowed form of T, like a &str for a CHash<String>
+    pub fn contains<Q>(&self, value: &Q) -> bool 
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        unwrap(self.contains_with(value, Lock::Wait))
+    }
 
-        let h1 = spawn(move || {
-            for i in 0..10 {
-                ch1.add(i);
-            }
-        });
-        let h2 = spawn(move || {
-            for i in 5..15 {
-                ch2.add(i);
-            }
-        });
+    pub fn try_contains<Q>(&self, value: &Q) -> Result<bool, Error>
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        self.contains_with(value, Lock::Try)
+    }
 
-        h1.join();
-        h2.join();
+    fn contains_with<Q>(&self, value: &Q, lock: Lock) -> Result<bool, Error>
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        self.recovering(lock, || self.on_current(value, lock, |table| table.contains(value, lock)))
+    }
 
-        println!("{:?}",ch);
-        assert!(ch.contains(1));
-        assert!(ch.contains(14));
+    /// number of repetitions of value, 0 if it is not there
+    pub fn count<Q>(&self, value: &Q) -> usize
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        unwrap(self.count_with(value, Lock::Wait))
     }
 
-    #[test]
-    fn chash_size() {
-        let ch = CHash::new();
-        assert_eq!(ch.size(),4);
-        ch.add(0);
-        ch.add(1);
-        assert_eq!(ch.size(),4);
-        ch.add(2);
-        assert_eq!(ch.size(),8);
-        for _ in 0..1000 {
-            ch.add(0);
+    pub fn try_count<Q>(&self, value: &Q) -> Result<usize, Error>
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        self.count_with(value, Lock::Try)
+    }
+
+    fn count_with<Q>(&self, value: &Q, lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        self.recovering(lock, || self.on_current(value, lock, |table| table.count(value, lock)))
+    }
+
+    /// sum of the repetitions of every value
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::SeqCst)
+    }
+
+    /// removes every repetition of value, returns false if it was not there
+    ///
+    /// the Container is left as a tombstone and only given back by the next resize
+    pub fn remove<Q>(&self, value: &Q) -> bool
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        unwrap(self.remove_with(value, Lock::Wait))
+    }
+
+    pub fn try_remove<Q>(&self, value: &Q) -> Result<bool, Error>
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        self.remove_with(value, Lock::Try)
+    }
+
+    fn remove_with<Q>(&self, value: &Q, lock: Lock) -> Result<bool, Error>
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        let removed = self.recovering(lock, || self.on_current(value, lock, |table| table.remove(value, lock)))?;
+        self.total.fetch_sub(removed, Ordering::SeqCst);
+        if removed > 0 {
+            self.removed_value(lock);
         }
-        assert_eq!(ch.size(),8);
+        Ok(removed > 0)
+    }
+
+    /// removes one repetition of value, returns the number of repetitions left or None if it was not there
+    pub fn remove_one<Q>(&self, value: &Q) -> Option<usize>
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        unwrap(self.remove_one_with(value, Lock::Wait))
+    }
+
+    pub fn try_remove_one<Q>(&self, value: &Q) -> Result<Option<usize>, Error>
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        self.remove_one_with(value, Lock::Try)
+    }
+
+    fn remove_one_with<Q>(&self, value: &Q, lock: Lock) -> Result<Option<usize>, Error>
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        let left = self.recovering(lock, || self.on_current(value, lock, |table| table.remove_one(value, lock)))?;
+        if left.is_some() {
+            self.total.fetch_sub(1, Ordering::SeqCst);
+        }
+        if left == Some(0) {
+            self.removed_value(lock);
+        }
+        Ok(left)
+    }
+
+    /// a value left the table, which shrinks if it is now below the low-water mark.
+    /// the removal is done, an error while shrinking is reported by the next call
+    fn removed_value(&self, lock: Lock)
+        where T: Hash
+    {
+        // a clear running at the same time may already have set it to 0
+        let distinct = match self.distinct.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |d| Some(d.saturating_sub(1))) {
+            Ok(d) | Err(d) => d.saturating_sub(1),
+        };
+        if self.min_load_factor == 0.0 {
+            return;
+        }
+        let size = match lock.read(&self.table) {
+            Ok(tables) => tables.current.size,
+            Err(_) => return,
+        };
+        if size > 4 && (distinct as f64) < size as f64 * self.min_load_factor {
+            let _ = self.recovering(lock, || self.smaller(lock));
+        }
+    }
+
+    /// moves the values into the smallest table holding them, dropping the tombstones on the way.
+    /// nothing is done when the table already has that size and no tombstone
+    fn smaller(&self, lock: Lock) -> Result<(), Error>
+        where T: Hash
+    {
+        let mut tables = lock.write(&self.table)?;
+        tables.finish()?;
+        self.migration_stalled.store(false, Ordering::SeqCst);
+        let mut distinct = 0;
+        tables.current.slots.for_each(Lock::Wait, |_,_| distinct += 1)?;
+        self.distinct.store(distinct, Ordering::SeqCst);
+        let used = max_used(tables.current.size, self.max_load_factor) - self.remaining.load(Ordering::SeqCst);
+        let size = size_for(distinct, self.max_load_factor);
+        if size >= tables.current.size && used == distinct {
+            return Ok(());
+        }
+        let size = std::cmp::min(size, tables.current.size);
+        tables.resize(size);
+        self.migration_next.store(0, Ordering::SeqCst);
+        self.migration_done.store(0, Ordering::SeqCst);
+        self.remaining.store(max_used(size, self.max_load_factor) - distinct, Ordering::SeqCst);
+        tables.finish().inspect_err(|_| self.migration_stalled.store(true, Ordering::SeqCst))
+    }
+
+    /// shrinks the table to the smallest size holding its values and drops the tombstones left by
+    /// removals, giving their memory back. the CHash is locked while the values are moved
+    pub fn shrink_to_fit(&self)
+        where T: Hash
+    {
+        unwrap(self.recovering(Lock::Wait, || self.smaller(Lock::Wait)))
+    }
+
+    /// removes every value, the table keeps its size, see shrink_to_fit.
+    /// the poisoned locks are cleared along with the values
+    pub fn clear(&self) {
+        let mut tables = self.table.write().unwrap_or_else(PoisonError::into_inner);
+        self.table.clear_poison();
+        let size = tables.current.size;
+        let cleared = Table::new(size, tables.current.hasher.clone());
+        let dropped = std::mem::replace(&mut tables.current, cleared);
+        tables.retired = tables.retired + dropped.slots.contention();
+        tables.take_old();
+        self.migration_next.store(0, Ordering::SeqCst);
+        self.migration_done.store(0, Ordering::SeqCst);
+        self.migration_stalled.store(false, Ordering::SeqCst);
+        self.remaining.store(max_used(size, self.max_load_factor), Ordering::SeqCst);
+        self.distinct.store(0, Ordering::SeqCst);
+        self.total.store(0, Ordering::SeqCst);
+    }
+
+    /// bytes used by the CHash and the Containers of its tables, both of them during a resize.
+    /// the memory the values own themselves, like the bytes of a String, is not counted
+    pub fn memory_usage(&self) -> usize {
+        let tables = self.table.read().unwrap_or_else(PoisonError::into_inner);
+        std::mem::size_of::<Self>()
+            + tables.current.slots.memory_usage()
+            + tables.old.as_ref().map_or(0, |old| old.slots.memory_usage())
+    }
+
+    pub fn size(&self) -> usize {
+        let tables = self.table.read().unwrap_or_else(PoisonError::into_inner);
+        tables.current.size()
+    }
+
+    pub fn iteratortable(self) -> IteratorTable<T>
+        where T: Hash
+    {
+        unwrap(self.recovering(Lock::Wait, || self.finish_resize(Lock::Wait)));
+        let tables = self.table.into_inner().unwrap_or_else(PoisonError::into_inner);
+        tables.current.iteratortable()
+    }
+
+    /// copies the (value, repetitions) pairs, each Container is only locked while it is cloned
+    ///
+    /// a resize in progress is finished first
+    pub fn snapshot(&self) -> Vec<(T, usize)>
+        where T: Clone + Hash
+    {
+        unwrap(self.snapshot_with(Lock::Wait))
+    }
+
+    pub fn try_snapshot(&self) -> Result<Vec<(T, usize)>, Error>
+        where T: Clone + Hash
+    {
+        self.snapshot_with(Lock::Try)
+    }
+
+    fn snapshot_with(&self, lock: Lock) -> Result<Vec<(T, usize)>, Error>
+        where T: Clone + Hash
+    {
+        self.recovering(lock, || {
+            let tables = self.settled(lock)?;
+            let mut pairs = Vec::new();
+            tables.current.slots.for_each(lock, |v,r| pairs.push((v.clone(), r)))?;
+            Ok(pairs)
+        })
+    }
+
+    /// the k values with the most repetitions, most repeated first. ties are broken arbitrarily
+    pub fn top_k(&self, k: usize) -> Vec<(T, usize)>
+        where T: Clone + Hash
+    {
+        if k == 0 {
+            return Vec::new();
+        }
+        let best = unwrap(self.recovering(Lock::Wait, || {
+            let tables = self.settled(Lock::Wait)?;
+            // min-heap of the k best so far, only the values entering it are cloned
+            let mut best: BinaryHeap<Reverse<Counted<T>>> = BinaryHeap::with_capacity(k+1);
+            tables.current.slots.for_each(Lock::Wait, |v,r| {
+                if best.len() < k {
+                    best.push(Reverse(Counted(r, v.clone())));
+                } else if best.peek().is_some_and(|Reverse(least)| least.0 < r) {
+                    best.pop();
+                    best.push(Reverse(Counted(r, v.clone())));
+                }
+            })?;
+            Ok(best)
+        }));
+        best.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(Counted(r,v))| (v,r))
+            .collect()
+    }
+
+    /// scans every Container once the resize in progress, if any, is finished
+    pub fn stats(&self) -> Stats
+        where T: Hash
+    {
+        let (tables, (distinct, max_probe_length, probes), used) = unwrap(self.recovering(Lock::Wait, || {
+            let tables = self.settled(Lock::Wait)?;
+            let probes = tables.current.probes()?;
+            let used = tables.current.slots.used(Lock::Wait)?;
+            Ok((tables, probes, used))
+        }));
+        let slots = tables.current.size;
+        Stats {
+            distinct,
+            total: self.total(),
+            slots,
+            load_factor: distinct as f64 / slots as f64,
+            tombstones: used.saturating_sub(distinct),
+            max_probe_length,
+            mean_probe_length: if distinct == 0 { 0.0 } else { probes as f64 / distinct as f64 },
+            resizes: tables.resizes,
+            contention: tables.retired + tables.current.slots.contention(),
+        }
+    }
+
+    /// read lock on the tables once no resize is in progress, so that every value is in the current one
+    fn settled(&self, lock: Lock) -> Result<RwLockReadGuard<'_, Tables<T, S, B>>, Error>
+        where T: Hash
+    {
+        loop {
+            self.finish_resize(lock)?;
+            let tables = lock.read(&self.table)?;
+            if tables.old.is_none() {
+                return Ok(tables);
+            }
+        }
+    }
+
+    /// Containers of the current table in use, tombstones included
+    fn used(&self, tables: &Tables<T, S, B>) -> usize {
+        max_used(tables.current.size, self.max_load_factor).saturating_sub(self.remaining.load(Ordering::SeqCst))
+    }
+
+    /// grows the table at once so that additional more values fit without resizing
+    #[cfg_attr(not(feature = "rayon"), allow(dead_code))]
+    fn grow_for(&mut self, additional: usize)
+        where T: Hash
+    {
+        let tables = unwrap(self.table.get_mut().map_err(|_| Error::Poisoned));
+        unwrap(tables.finish());
+        let used = max_used(tables.current.size, self.max_load_factor) - self.remaining.load(Ordering::SeqCst);
+        let size = size_for(used.saturating_add(additional), self.max_load_factor);
+        if size <= tables.current.size {
+            return;
+        }
+        let mut grown = Self::with_size(size, self.max_load_factor, tables.current.hasher.clone());
+        grown.max_repetitions = self.max_repetitions;
+        grown.min_load_factor = self.min_load_factor;
+        grown.poison_policy = self.poison_policy;
+        let grown_tables = grown.table.get_mut().unwrap();
+        grown_tables.resizes = tables.resizes + 1;
+        grown_tables.retired = tables.retired + tables.current.slots.contention();
+        let old = std::mem::replace(self, grown);
+        for (v,r) in old.table.into_inner().unwrap_or_else(PoisonError::into_inner).current.slots.into_pairs() {
+            self.add_n(v, r);
+        }
+    }
+}
+
+/// a value ordered by its number of repetitions only, for top_k
+struct Counted<T>(usize, T);
+
+impl<T> PartialEq for Counted<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T> Eq for Counted<T> {}
+
+impl<T> PartialOrd for Counted<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Counted<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl<T: PartialEq + Hash, S: BuildHasher + Clone> CHash<T, S, LockedSlots<T>> {
+    /// read locks the table and every Container until the returned guard is dropped
+    ///
+    /// other threads can still read but their add and remove calls wait for the guard,
+    /// calling them from the thread holding it deadlocks. see snapshot for a non blocking copy
+    pub fn iter(&self) -> TableRef<'_, T, S> {
+        unwrap(self.recovering(Lock::Wait, || {
+            let containers = Held::new(self.settled(Lock::Wait)?, |tables| {
+                tables.current.slots.containers
+                    .iter()
+                    .map(|c| c.read().map_err(|_| Error::Poisoned))
+                    .collect::<Result<_, _>>()
+                    .map(Some)
+            })?;
+            Ok(TableRef { containers: containers.expect("the Containers are always read") })
+        }))
+    }
+}
+
+impl<T, S: BuildHasher + Clone + Default, B: Slots<T>> Default for CHash<T, S, B> {
+    fn default() -> Self {
+        Self::with_capacity_and_max_load_factor(0, DEFAULT_MAX_LOAD_FACTOR)
+    }
+}
+
+type ContainerGuards<'a, T> = Vec<RwLockReadGuard<'a, Container<T>>>;
+
+/// read guard over a CHash, see CHash::iter
+pub struct TableRef<'a, T, S = RandomState> {
+    /// the Container guards, released before the tables they borrow
+    containers: Held<'a, Tables<T, S, LockedSlots<T>>, ContainerGuards<'a, T>>,
+}
+
+impl<T, S> TableRef<'_, T, S> {
+    /// (value, repetitions) pairs
+    pub fn iter(&self) -> impl Iterator<Item = (&T, usize)> + '_ {
+        self.containers.iter().filter_map(|c| match &**c {
+            Container::ElemRepeat(v,r) => Some((v, *r)),
+            _ => None,
+        })
+    }
+
+    /// each value repeated as many times as it was added
+    pub fn iter_expanded(&self) -> impl Iterator<Item = T> + '_
+        where T: Clone
+    {
+        self.iter().flat_map(|(v,r)| std::iter::repeat_n(v, r).cloned())
+    }
+}
+
+pub struct IteratorTable<T> {
+    containers: Vec<(T, usize)>,
+    current_index_containers: usize,
+    remaining_numbers_rep: usize,
+}
+
+impl<T> IteratorTable<T> {
+    fn new(containers: Vec<(T, usize)>) -> Self {
+        let remaining_numbers_rep = containers.first().map_or(0, |&(_,r)| r);
+        IteratorTable{ containers,
+                    current_index_containers: 0,
+                    remaining_numbers_rep}
+    }
+}
+
+impl<T: Copy> Iterator for IteratorTable<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.current_index_containers < self.containers.len() {
+            if self.remaining_numbers_rep>0 {
+                self.remaining_numbers_rep -= 1;
+                return Some(self.containers[self.current_index_containers].0);
+            }
+            self.current_index_containers += 1;
+            if let Some(&(_,repeatitions)) = self.containers.get(self.current_index_containers) {
+                self.remaining_numbers_rep = repeatitions;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread::spawn;
+    use std::time::{Duration, Instant};
+    #[test]
+    fn it_works() {
+        let table: Arc<Table<_>> = Arc::new(Table::new(4, RandomState::new()));
+        let t1 = table.clone();
+        let handler1 = spawn(move || {
+            t1.add(0,1).unwrap();
+            t1.add(1,1).unwrap();
+        });
+        let t2 = table.clone();
+        let handler2 = spawn(move || {
+            t2.add(1,1).unwrap();
+            t2.add(4,1).unwrap();
+        });
+        handler1.join().unwrap();
+        handler2.join().unwrap();
+        assert_eq!(table.contains(&1, Lock::Wait), Ok(true));
+        assert_eq!(table.contains(&0, Lock::Wait), Ok(true));
+        assert_eq!(table.contains(&4, Lock::Wait), Ok(true));
+        assert_eq!(table.contains(&2, Lock::Wait), Ok(false));
+        assert_eq!(table.count(&1, Lock::Wait), Ok(2));
+    }
+
+    #[test]
+    fn iterator() {
+        let table: Table<_> = Table::new(4, RandomState::new());
+        table.add(4,1).unwrap();
+        table.add(0,1).unwrap();
+        table.add(1,1).unwrap();
+        table.add(0,1).unwrap();
+        table.add(2,1).unwrap();
+        let mut output = table.iteratortable().collect::<Vec<_>>();
+        output.sort();
+        assert_eq!(vec![0,0,1,2,4],output);
+    }
+
+    fn three_spawns<B: Slots<i32> + Send + Sync + 'static>() {
+        let table: Arc<Table<i32, RandomState, B>> = Arc::new(Table::new(10_000, RandomState::new()));
+        let (t1,t2,t3) = (table.clone(), table.clone(), table.clone());
+        let h1 = spawn(move || {
+            for i in 0..4000 {
+                t1.add(i,1).unwrap();
+            }
+        });
+        let h2 = spawn(move || {
+            for i in 4000..7000 {
+                t2.add(i,1).unwrap();
+            }
+        });
+        let h3 = spawn(move || {
+            for i in 7000..10000 {
+                t3.add(i,1).unwrap();
+            }
+        });
+        h1.join().unwrap();
+        h2.join().unwrap();
+        h3.join().unwrap();
+        assert!((0..10000).all(|i| table.contains(&i, Lock::Wait) == Ok(true)));
+        assert_eq!(table.contains(&10000, Lock::Wait), Ok(false));
+    }
+
+    // les temps de chaque stockage face à une HashMap : cargo bench --bench chash
+    #[test]
+    fn beaucoup() {
+        three_spawns::<LockedSlots<i32>>();
+        three_spawns::<AtomicSlots<i32>>();
+    }
+
+    #[test]
+    fn chash_test() {
+        let ch = CHash::new();
+        for i in 0..6 {
+            ch.add(i);
+        }
+        //let table = ch.table.into_inner().unwrap();
+        //println!("{:?}",table.iteratortable().collect::<Vec<_>>());
+        assert!(ch.contains(&0));
+    }
+
+    #[test]
+    fn chash_threads_test() {
+        let ch = Arc::new(CHash::new());
+        let ch1 = ch.clone();
+        let ch2 = ch.clone();
+
+        let h1 = spawn(move || {
+            for i in 0..10 {
+                ch1.add(i);
+            }
+        });
+        let h2 = spawn(move || {
+            for i in 5..15 {
+                ch2.add(i);
+            }
+        });
+
+        h1.join().unwrap();
+        h2.join().unwrap();
+
+        println!("{:?}",ch);
+        assert!(ch.contains(&1));
+        assert!(ch.contains(&14));
+        // both threads added 5..10
+        for i in 0..15 {
+            assert_eq!(ch.count(&i), if (5..10).contains(&i) { 2 } else { 1 });
+        }
+        assert_eq!(ch.total(), 20);
+    }
+
+    #[test]
+    fn chash_size() {
+        let ch = CHash::new();
+        assert_eq!(ch.size(),4);
+        ch.add(0);
+        ch.add(1);
+        assert_eq!(ch.size(),4);
+        ch.add(2);
+        assert_eq!(ch.size(),8);
+        for _ in 0..1000 {
+            ch.add(0);
+        }
+        assert_eq!(ch.size(),8);
+    }
+
+    #[test]
+    fn remove() {
+        let ch = CHash::new();
+        for i in 0..50 {
+            ch.add(i);
+        }
+        ch.add(7);
+        for i in (0..50).step_by(3) {
+            assert!(ch.remove(&i));
+        }
+        assert!(!ch.remove(&0));
+        for i in 0..50 {
+            assert_eq!(ch.contains(&i), i%3 != 0);
+        }
+        assert_eq!(ch.remove_one(&7), Some(1));
+        assert_eq!(ch.remove_one(&7), Some(0));
+        assert_eq!(ch.remove_one(&7), None);
+        assert!(!ch.contains(&7));
+    }
+
+    #[test]
+    fn double_purges_tombstones() {
+        let mut tables = Tables { current: Table::<_>::new(8, RandomState::new()), old: None, resizes: 0, retired: Contention::default() };
+        for i in 0..6 {
+            tables.current.add(i,1).unwrap();
+        }
+        for i in 0..3 {
+            tables.current.remove(&i, Lock::Wait).unwrap();
+        }
+        tables.double();
+        tables.finish().unwrap();
+        assert_eq!(tables.current.size(), 16);
+        assert!(tables.current.slots.containers.iter().all(|c| !matches!(*c.read().unwrap(), Container::Tombstone)));
+        let mut output = tables.current.iteratortable().collect::<Vec<_>>();
+        output.sort();
+        assert_eq!(vec![3,4,5],output);
+    }
+
+    /// adds and removes the same values over and over, the tombstones must not make the table grow
+    fn churn<B: Slots<u64>>() {
+        let ch: CHash<u64, RandomState, B> = CHash::default();
+        for _ in 0..100_000 {
+            ch.add(7);
+            ch.remove(&7);
+        }
+        assert!(ch.size() <= 8);
+        for _ in 0..20 {
+            for i in 0..1000 {
+                ch.add(i);
+            }
+            for i in 0..1000 {
+                ch.remove(&i);
+            }
+        }
+        assert!(ch.size() <= 4096);
+        assert!(ch.stats().tombstones <= 1000);
+    }
+
+    #[test]
+    fn churn_keeps_size() {
+        churn::<LockedSlots<u64>>();
+        churn::<RobinHoodSlots<u64>>();
+        churn::<AtomicSlots<u64>>();
+    }
+
+    #[test]
+    fn iter_guard() {
+        let ch = CHash::new();
+        for i in 0..5 {
+            ch.add(i);
+        }
+        ch.add(3);
+        ch.remove(&0);
+        let guard = ch.iter();
+        let mut pairs = guard.iter().map(|(v,r)| (*v,r)).collect::<Vec<_>>();
+        pairs.sort();
+        assert_eq!(vec![(1,1),(2,1),(3,2),(4,1)], pairs);
+        let mut expanded = guard.iter_expanded().collect::<Vec<_>>();
+        expanded.sort();
+        assert_eq!(vec![1,2,3,3,4], expanded);
+        // readers are not blocked by the guard
+        assert!(ch.contains(&3));
+        drop(guard);
+        let mut snapshot = ch.snapshot();
+        snapshot.sort();
+        assert_eq!(vec![(1,1),(2,1),(3,2),(4,1)], snapshot);
+    }
+
+    #[test]
+    fn snapshot_while_adding() {
+        let ch = Arc::new(CHash::new());
+        let ch1 = ch.clone();
+        let h = spawn(move || {
+            for i in 0..1000 {
+                ch1.add(i%100);
+            }
+        });
+        for _ in 0..10 {
+            let snapshot = ch.snapshot();
+            assert!(snapshot.iter().all(|&(v,r)| v < 100 && r <= 10));
+        }
+        h.join().unwrap();
+        let total: usize = ch.snapshot().iter().map(|&(_,r)| r).sum();
+        assert_eq!(total, 1000);
+    }
+
+    #[test]
+    fn atomic_chash() {
+        let ch = Arc::new(AtomicCHash::<u64>::default());
+        let handlers: Vec<_> = (0..4)
+            .map(|t| {
+                let ch = ch.clone();
+                spawn(move || {
+                    for i in 0..1000u64 {
+                        ch.add(i%(100*(t+1)));
+                    }
+                })
+            })
+            .collect();
+        for h in handlers {
+            h.join().unwrap();
+        }
+        assert!(ch.contains(&399));
+        assert!(!ch.contains(&400));
+        assert!(ch.remove(&0));
+        assert!(!ch.contains(&0));
+        let total: usize = ch.snapshot().iter().map(|&(_,r)| r).sum();
+        assert_eq!(total, 4000-10-5-4-3);
+    }
+
+    #[test]
+    fn atomic_reserved_value() {
+        let ch = AtomicCHash::<u64>::default();
+        let stats = ch.stats();
+        assert_eq!(ch.try_add(u64::MAX), Err(Error::ReservedValue));
+        assert_eq!(ch.try_add_n(u64::MAX, 0), Err(Error::ReservedValue));
+        assert_eq!(ch.stats(), stats);
+        assert!(!ch.contains(&u64::MAX));
+        // nothing was reserved for it, the table fills up to its load factor as before
+        for i in 0..max_used(stats.slots, DEFAULT_MAX_LOAD_FACTOR) as u64 {
+            assert_eq!(ch.try_add(i), Ok(AddOutcome::Inserted));
+        }
+        assert_eq!(ch.size(), stats.slots);
+        let ch = Arc::new(ch);
+        let c = ch.clone();
+        assert!(spawn(move || c.add(u64::MAX)).join().is_err());
+    }
+
+    #[test]
+    fn atomic_readd_keeps_size() {
+        // a removed value added back reuses its slot, the reservation made for it used to be kept
+        let ch = AtomicCHash::<u64>::default();
+        ch.add(7);
+        let size = ch.size();
+        for _ in 0..10_000 {
+            ch.remove(&7);
+            assert_eq!(ch.add(7), AddOutcome::Inserted);
+        }
+        assert_eq!(ch.size(), size);
+        assert_eq!(ch.total(), 1);
+    }
+
+    #[test]
+    fn incremental_resize() {
+        let ch = CHash::new();
+        let mut i = 0;
+        while ch.size() < 64 {
+            ch.add(i);
+            i += 1;
+        }
+        // the add which started the resize moved one of the two chunks of the old table
+        assert!(ch.table.read().unwrap().old.is_some());
+        assert!(ch.contains(&0));
+        assert!(ch.table.read().unwrap().old.is_none());
+        for j in 0..i {
+            assert!(ch.contains(&j));
+        }
+        for i in i..1000 {
+            ch.add(i);
+            assert!(ch.contains(&(i/2)));
+        }
+        assert_eq!(ch.snapshot().len(), 1000);
+    }
+
+    /// threads adding the same values while the table grows, each then removing one of them
+    fn resize_threads<B: Slots<u32> + Send + Sync + 'static>() {
+        let ch = Arc::new(CHash::<u32, RandomState, B>::default());
+        let handlers: Vec<_> = (0..4)
+            .map(|t| {
+                let ch = ch.clone();
+                spawn(move || {
+                    for i in 0..5000 {
+                        ch.add(i);
+                        if i%7 == 0 {
+                            assert!(ch.contains(&i));
+                        }
+                    }
+                    assert!(ch.remove_one(&t).is_some());
+                    assert_eq!(ch.remove_one(&(10_000+t)), None);
+                })
+            })
+            .collect();
+        for h in handlers {
+            h.join().unwrap_or_else(|_| panic!("a thread failed"));
+        }
+        assert_eq!(ch.total(), 4*5000-4);
+        assert!(ch.remove(&4999));
+        assert!(!ch.contains(&4999));
+        let snapshot = ch.snapshot();
+        assert_eq!(snapshot.len(), 4999);
+        assert!(snapshot.iter().all(|&(v,r)| r == if v < 4 {3} else {4}));
+    }
+
+    #[test]
+    fn incremental_resize_threads() {
+        resize_threads::<LockedSlots<u32>>();
+        resize_threads::<RobinHoodSlots<u32>>();
+        resize_threads::<AtomicSlots<u32>>();
+    }
+
+    #[test]
+    fn capacity_and_load_factor() {
+        let ch = CHash::with_capacity(100);
+        assert_eq!(ch.size(), 256);
+        for i in 0..192 {
+            ch.add(i);
+        }
+        assert_eq!(ch.size(), 256);
+        ch.add(192);
+        assert_eq!(ch.size(), 512);
+
+        let ch = CHash::with_max_load_factor(0.5);
+        for i in 0..16 {
+            ch.add(i);
+        }
+        assert_eq!(ch.size(), 32);
+        ch.add(16);
+        assert_eq!(ch.size(), 64);
+    }
+
+    #[test]
+    fn one_resize_per_threshold() {
+        let ch = Arc::new(CHash::new());
+        let handlers: Vec<_> = (0..8)
+            .map(|t| {
+                let ch = ch.clone();
+                spawn(move || {
+                    for i in 0..2000 {
+                        ch.add(i*8+t);
+                    }
+                })
+            })
+            .collect();
+        for h in handlers {
+            h.join().unwrap();
+        }
+        let size = ch.size();
+        assert_eq!(size, 32_768);
+        assert_eq!(ch.table.read().unwrap().resizes, 13);
+        assert!(ch.remaining.load(Ordering::SeqCst) <= max_used(size, 0.75));
+    }
+
+    fn stress_probing<B: Slots<u32> + Send + Sync + 'static>(max_load_factor: f64) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let ch: Arc<CHash<u32, RandomState, B>> = Arc::new(CHash::with_capacity_and_max_load_factor(0, max_load_factor));
+        let handlers: Vec<_> = (0..6)
+            .map(|t| {
+                let ch = ch.clone();
+                let sender = sender.clone();
+                spawn(move || {
+                    for i in 0..3000 {
+                        ch.add(i%(500*(t+1)));
+                        if i%3 == 0 {
+                            ch.remove(&(i/2));
+                        }
+                        ch.contains(&(100_000+i));
+                    }
+                    sender.send(()).unwrap();
+                })
+            })
+            .collect();
+        for _ in 0..6 {
+            receiver
+                .recv_timeout(Duration::from_secs(60))
+                .expect("probing did not terminate");
+        }
+        for h in handlers {
+            h.join().unwrap();
+        }
+        let tables = ch.table.read().unwrap();
+        assert!(max_used(tables.current.size, max_load_factor) <= tables.current.size - 2);
+    }
+
+    #[test]
+    fn probing_terminates() {
+        stress_probing::<LockedSlots<u32>>(1.0);
+        stress_probing::<LockedSlots<u32>>(0.9);
+        stress_probing::<AtomicSlots<u32>>(1.0);
+        stress_probing::<AtomicSlots<u32>>(0.5);
+    }
+
+    #[test]
+    fn deterministic_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+        type Deterministic = BuildHasherDefault<DefaultHasher>;
+
+        let ch1: CHash<u32, Deterministic> = CHash::with_hasher(Deterministic::default());
+        let ch2: CHash<u32, Deterministic> = CHash::with_capacity_and_hasher(0, Deterministic::default());
+        for i in 0..500 {
+            ch1.add(i);
+            ch2.add(i);
+        }
+        // same hasher in every table the maps grew into, so the same layout
+        assert_eq!(ch1.snapshot(), ch2.snapshot());
+        assert!(ch1.contains(&499));
+    }
+
+    #[test]
+    fn custom_hasher() {
+        /// multiplicative hash, the kind of fast non random hasher that can be plugged in
+        #[derive(Default)]
+        struct Mul(u64);
+        impl std::hash::Hasher for Mul {
+            fn finish(&self) -> u64 { self.0 }
+            fn write(&mut self, bytes: &[u8]) {
+                for &b in bytes {
+                    self.0 = (self.0.rotate_left(5) ^ b as u64).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+                }
+            }
+        }
+        let ch: AtomicCHash<u64, std::hash::BuildHasherDefault<Mul>> = CHash::default();
+        for i in 0..1000 {
+            ch.add(i%300);
+        }
+        assert!(ch.contains(&299));
+        assert!(!ch.contains(&300));
+        assert_eq!(ch.snapshot().iter().map(|&(_,r)| r).sum::<usize>(), 1000);
+    }
+
+    #[test]
+    fn counting() {
+        let ch = CHash::new();
+        for token in "le chat et le chien et le rat".split(' ') {
+            ch.add(token);
+        }
+        ch.add_n("souris", 5);
+        ch.add_n("rien", 0);
+        assert!(!ch.contains("rien"));
+        assert_eq!(ch.count(&"le"), 3);
+        assert_eq!(ch.count(&"loup"), 0);
+        assert_eq!(ch.total(), 13);
+        assert_eq!(ch.top_k(3), vec![("souris",5),("le",3),("et",2)]);
+        assert_eq!(ch.top_k(0), vec![]);
+        assert_eq!(ch.top_k(100).len(), 6);
+        ch.remove(&"souris");
+        ch.remove_one(&"le");
+        assert_eq!(ch.total(), 7);
+        assert_eq!(ch.top_k(1)[0].1, 2);
+    }
+
+    #[test]
+    fn counting_threads() {
+        let ch = Arc::new(AtomicCHash::<u32>::default());
+        let handlers: Vec<_> = (0..4)
+            .map(|_| {
+                let ch = ch.clone();
+                spawn(move || {
+                    for i in 0..1000 {
+                        ch.add_n(i%50, (i%3) as usize);
+                    }
+                })
+            })
+            .collect();
+        for h in handlers {
+            h.join().unwrap();
+        }
+        let expected = (0..1000).map(|i| i%3).sum::<usize>() * 4;
+        assert_eq!(ch.total(), expected);
+        assert_eq!(ch.snapshot().iter().map(|&(_,r)| r).sum::<usize>(), expected);
+        let top = ch.top_k(5);
+        assert_eq!(top.len(), 5);
+        assert!(top.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert_eq!(top[0].1, ch.count(&top[0].0));
+    }
+
+    #[test]
+    fn max_repetitions() {
+        let ch = CHash::with_max_repetitions(3);
+        assert_eq!(ch.add("client"), AddOutcome::Inserted);
+        assert_eq!(ch.add("client"), AddOutcome::Incremented);
+        assert_eq!(ch.add("client"), AddOutcome::Incremented);
+        assert_eq!(ch.add("client"), AddOutcome::Saturated);
+        assert_eq!(ch.count(&"client"), 3);
+        assert_eq!(ch.add_n("autre", 5), AddOutcome::Saturated);
+        assert_eq!(ch.count(&"autre"), 3);
+        assert_eq!(ch.total(), 6);
+        // a repetition given back makes room for one more
+        ch.remove_one(&"client");
+        assert_eq!(ch.add("client"), AddOutcome::Incremented);
+        assert_eq!(ch.add("client"), AddOutcome::Saturated);
+    }
+
+    #[test]
+    fn max_repetitions_threads() {
+        let mut ch = AtomicCHash::<u32>::default();
+        ch.set_max_repetitions(100);
+        let ch = Arc::new(ch);
+        let handlers: Vec<_> = (0..4)
+            .map(|_| {
+                let ch = ch.clone();
+                spawn(move || {
+                    (0..1000).filter(|&i| ch.add(i%8) != AddOutcome::Saturated).count()
+                })
+            })
+            .collect();
+        let accepted: usize = handlers.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(accepted, 800);
+        assert_eq!(ch.total(), 800);
+        assert!(ch.snapshot().iter().all(|&(_,r)| r == 100));
+    }
+
+    #[test]
+    fn borrowed_lookups() {
+        let ch: CHash<String> = CHash::new();
+        for token in "GET /index GET /login POST /login".split(' ') {
+            ch.add(token.to_string());
+        }
+        assert!(ch.contains("GET"));
+        assert!(!ch.contains("PUT"));
+        assert_eq!(ch.count("/login"), 2);
+        assert_eq!(ch.remove_one("/login"), Some(1));
+        assert!(ch.remove("GET"));
+        assert_eq!(ch.count("GET"), 0);
+        assert_eq!(ch.total(), 3);
+    }
+
+    /// distance to its home of the value in each Container, for a table filled up to load
+    fn distances<B: Slots<u64>>(load: f64) -> Vec<Option<usize>> {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let size = 1 << 16;
+        let table: Table<u64, BuildHasherDefault<DefaultHasher>, B> = Table::new(size, Default::default());
+        for i in 0..(size as f64 * load) as u64 {
+            table.add(i, 1).unwrap();
+        }
+        let mut distances = vec![None; size];
+        table.slots.for_each_indexed(0..size, Lock::Wait, |index,v,_| {
+            distances[index] = Some((index + size - table.home(v)) % size);
+        }).unwrap();
+        distances
+    }
+
+    /// probes of a lookup for each value stored, then of a lookup for a missing value from each home.
+    /// with linear probing a miss scans up to an empty Container, with Robin Hood it also stops at
+    /// the first value closer to its home than the one looked for
+    fn probe_lengths(distances: &[Option<usize>], robin_hood: bool) -> (Vec<usize>, Vec<usize>) {
+        let size = distances.len();
+        let hits = distances.iter().flatten().map(|d| d+1).collect();
+        let misses = (0..size)
+            .map(|home| {
+                let mut probes = 1;
+                while let Some(d) = distances[(home+probes-1)%size] {
+                    if robin_hood && d < probes-1 {
+                        break;
+                    }
+                    probes += 1;
+                }
+                probes
+            })
+            .collect();
+        (hits, misses)
+    }
+
+    /// length of the p-th probe of lengths, sorted
+    fn percentile(lengths: &[usize], p: f64) -> usize {
+        let mut sorted = lengths.to_vec();
+        sorted.sort_unstable();
+        sorted[((sorted.len()-1) as f64 * p) as usize]
+    }
+
+    #[test]
+    fn probe_length_distributions() {
+        for &load in &[0.5, 0.75, 0.9] {
+            let (linear_hits, linear_misses) = probe_lengths(&distances::<LockedSlots<u64>>(load), false);
+            let (robin_hits, robin_misses) = probe_lengths(&distances::<RobinHoodSlots<u64>>(load), true);
+            // same values, so the same total displacement, but Robin Hood evens it out
+            assert_eq!(linear_hits.iter().sum::<usize>(), robin_hits.iter().sum::<usize>());
+            assert!(robin_hits.iter().max() <= linear_hits.iter().max());
+            assert!(percentile(&robin_hits, 0.99) <= percentile(&linear_hits, 0.99));
+            assert!(robin_misses.iter().sum::<usize>() <= linear_misses.iter().sum::<usize>());
+            assert!(percentile(&robin_misses, 0.99) <= percentile(&linear_misses, 0.99));
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let ch = CHash::with_capacity(100);
+        let empty = ch.stats();
+        assert_eq!((empty.distinct, empty.total, empty.slots, empty.resizes), (0, 0, 256, 0));
+        assert_eq!(empty.mean_probe_length, 0.0);
+        for i in 0..300u32 {
+            ch.add_n(i, 2);
+        }
+        ch.remove(&0);
+        ch.remove(&1);
+        let stats = ch.stats();
+        assert_eq!(stats.distinct, 298);
+        assert_eq!(stats.total, 596);
+        assert_eq!(stats.slots, 512);
+        assert_eq!(stats.load_factor, 298.0/512.0);
+        assert_eq!(stats.tombstones, 2);
+        assert_eq!(stats.resizes, 1);
+        assert!(stats.max_probe_length >= 1);
+        assert!(stats.mean_probe_length >= 1.0 && stats.mean_probe_length <= stats.max_probe_length as f64);
+        let atomic = AtomicCHash::<u64>::default();
+        atomic.add(3);
+        assert_eq!(atomic.stats().max_probe_length, 1);
+        // the tombstones left by the removals are dropped by the resize they cause
+        let ch = CHash::with_capacity(100);
+        for i in 0..150u32 {
+            ch.add(i);
+        }
+        for i in 0..100 {
+            ch.remove(&i);
+        }
+        for i in 150..250 {
+            ch.add(i);
+        }
+        let stats = ch.stats();
+        assert_eq!((stats.distinct, stats.resizes, stats.tombstones), (150, 1, 0));
+        // a Robin Hood removal leaves no tombstone at all
+        let robin_hood = RobinHoodCHash::<u32>::default();
+        for i in 0..10 {
+            robin_hood.add(i);
+        }
+        robin_hood.remove(&3);
+        assert_eq!(robin_hood.stats().tombstones, 0);
+    }
+
+    #[test]
+    fn stats_contention() {
+        let ch = Arc::new(CHash::new());
+        ch.add(1u32);
+        let guard = ch.iter();
+        let adder = {
+            let ch = ch.clone();
+            spawn(move || {ch.add(1);})
+        };
+        // the add waits for the read guard on the Container of 1
+        let start = Instant::now();
+        while ch.table.read().unwrap().current.slots.contention().writes == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10), "the add never waited");
+            std::thread::yield_now();
+        }
+        drop(guard);
+        adder.join().unwrap();
+        assert_eq!(ch.count(&1), 2);
+        assert!(ch.stats().contention.writes >= 1);
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let ch = CHash::new();
+        for i in 0..10_000u32 {
+            ch.add_n(i, 2);
+        }
+        let big = ch.memory_usage();
+        for i in 10..10_000 {
+            ch.remove(&i);
+        }
+        assert_eq!(ch.size(), 16384);
+        ch.shrink_to_fit();
+        // 10 values fit in 16 Containers at 0.75
+        assert_eq!(ch.size(), 16);
+        assert!(ch.memory_usage() < big/100);
+        let stats = ch.stats();
+        assert_eq!((stats.distinct, stats.total, stats.tombstones, stats.resizes), (10, 20, 0, 13));
+        assert!((0..10).all(|i| ch.count(&i) == 2));
+        // the tombstones are dropped even when the size stays
+        ch.remove(&0);
+        ch.shrink_to_fit();
+        assert_eq!((ch.size(), ch.stats().tombstones), (16, 0));
+        for i in 0..100 {
+            ch.add(i);
+        }
+        assert_eq!(ch.total(), 118);
+    }
+
+    #[test]
+    fn shrink_threads() {
+        let ch = Arc::new(CHash::new());
+        let adders: Vec<_> = (0..2)
+            .map(|_| {
+                let ch = ch.clone();
+                spawn(move || {
+                    for i in 0..20_000u32 {
+                        ch.add(i%5000);
+                        if i%1000 == 0 {
+                            ch.shrink_to_fit();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for i in 0..1000 {
+            ch.add_n(100_000 + i, 3);
+            ch.remove(&(100_000 + i));
+            if i%100 == 0 {
+                ch.shrink_to_fit();
+            }
+        }
+        for h in adders {
+            h.join().unwrap();
+        }
+        ch.shrink_to_fit();
+        assert_eq!(ch.total(), 40_000);
+        assert!((0..5000).all(|i| ch.count(&i) == 8));
+        assert_eq!(ch.size(), 8192);
+    }
+
+    fn low_water_mark<B: Slots<u32>>() {
+        let mut ch: CHash<u32, RandomState, B> = CHash::with_hasher(RandomState::new());
+        ch.set_min_load_factor(0.1);
+        for i in 0..1000 {
+            ch.add(i);
+        }
+        assert_eq!(ch.size(), 2048);
+        // the table shrinks to 512 Containers when 204 values are left, which is above 0.1*512
+        for i in 0..900 {
+            ch.remove(&i);
+        }
+        assert_eq!(ch.size(), 512);
+        for i in 900..960 {
+            assert_eq!(ch.remove_one(&i), Some(0));
+        }
+        assert_eq!(ch.size(), 128);
+        assert_eq!(ch.total(), 40);
+        assert!((960..1000).all(|i| ch.count(&i) == 1));
+        assert!(!ch.contains(&0));
+    }
+
+    #[test]
+    fn low_water_marks() {
+        low_water_mark::<LockedSlots<u32>>();
+        low_water_mark::<AtomicSlots<u32>>();
+        low_water_mark::<RobinHoodSlots<u32>>();
+    }
+
+    #[test]
+    fn clear() {
+        let ch = CHash::new();
+        for i in 0..100u32 {
+            ch.add(i);
+        }
+        let size = ch.size();
+        ch.clear();
+        assert_eq!((ch.total(), ch.size(), ch.stats().distinct), (0, size, 0));
+        assert!(!ch.contains(&1));
+        for i in 0..100 {
+            ch.add(i);
+        }
+        // the Containers were given back, no resize was needed
+        assert_eq!((ch.total(), ch.size()), (100, size));
+        ch.clear();
+        ch.shrink_to_fit();
+        assert_eq!(ch.size(), 4);
+        // clearing also drops the poisoned Containers
+        let ch = poisoned();
+        ch.clear();
+        assert_eq!(ch.try_add(Fragile(1, false)), Ok(AddOutcome::Inserted));
+    }
+
+    /// compares like its number, unless one of the two values is fragile : the comparison panics
+    #[derive(Debug, Clone, Copy)]
+    struct Fragile(u32, bool);
+
+    impl PartialEq for Fragile {
+        fn eq(&self, other: &Self) -> bool {
+            assert!(!self.1 && !other.1, "fragile comparison");
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Fragile {}
+
+    impl Hash for Fragile {
+        fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+            self.0.hash(state);
+        }
+    }
+
+    type Deterministic = std::hash::BuildHasherDefault<std::collections::hash_map::DefaultHasher>;
+
+    /// a CHash holding 1 and 2 whose Container of 1 was poisoned by a panicking add
+    fn poisoned() -> CHash<Fragile, Deterministic> {
+        let ch = CHash::with_capacity_and_hasher(8, Deterministic::default());
+        ch.add(Fragile(1, false));
+        ch.add(Fragile(2, false));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| ch.add(Fragile(1, true))));
+        assert!(panicked.is_err());
+        ch
+    }
+
+    #[test]
+    fn poison_propagated() {
+        let ch = poisoned();
+        assert_eq!(ch.poison_policy(), PoisonPolicy::Propagate);
+        assert_eq!(ch.try_contains(&Fragile(1, false)), Err(Error::Poisoned));
+        assert_eq!(ch.try_add(Fragile(1, false)), Err(Error::Poisoned));
+        assert_eq!(ch.try_remove_one(&Fragile(1, false)), Err(Error::Poisoned));
+        assert_eq!(ch.try_snapshot().map(|pairs| pairs.len()), Err(Error::Poisoned));
+        // the probe chain of 2 does not go through the Container of 1
+        assert_eq!(ch.try_count(&Fragile(2, false)), Ok(1));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| ch.contains(&Fragile(1, false))));
+        assert!(panicked.is_err());
+        assert_eq!(ch.total(), 2);
+    }
+
+    #[test]
+    fn poison_cleared() {
+        let mut ch = poisoned();
+        ch.set_poison_policy(PoisonPolicy::Clear);
+        // the Container of 1 is cleared, 1 is lost and the total counted again
+        assert_eq!(ch.try_contains(&Fragile(1, false)), Ok(false));
+        assert_eq!(ch.total(), 1);
+        assert_eq!(ch.try_add(Fragile(1, false)), Ok(AddOutcome::Inserted));
+        assert_eq!(ch.count(&Fragile(1, false)), 1);
+        assert_eq!(ch.try_remove(&Fragile(2, false)), Ok(true));
+        assert_eq!(ch.snapshot().len(), 1);
+        // the policy is kept by the tables the CHash grows into
+        for i in 3..100 {
+            ch.add(Fragile(i, false));
+        }
+        assert_eq!(ch.total(), 98);
+    }
+
+    #[test]
+    fn poisoned_table_lock() {
+        // a panic during the resize poisons the lock of the tables
+        let mut ch: CHash<Fragile, Deterministic> = CHash::with_capacity_and_hasher(2, Deterministic::default());
+        ch.add(Fragile(1, false));
+        ch.add(Fragile(2, false));
+        ch.add(Fragile(3, false));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut tables = ch.table.write().unwrap();
+            tables.double();
+            panic!("interrupted resize");
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(ch.try_add(Fragile(4, false)), Err(Error::Poisoned));
+        assert_eq!(ch.try_count(&Fragile(1, false)), Err(Error::Poisoned));
+        ch.set_poison_policy(PoisonPolicy::Clear);
+        // the interrupted resize is finished, no value was lost
+        assert_eq!(ch.try_count(&Fragile(1, false)), Ok(1));
+        assert_eq!(ch.try_add(Fragile(4, false)), Ok(AddOutcome::Inserted));
+        assert_eq!(ch.total(), 4);
+        assert!(ch.table.read().unwrap().old.is_none());
+    }
+
+    #[test]
+    fn would_block() {
+        let ch = CHash::new();
+        ch.add(1u32);
+        let tables = ch.table.write().unwrap();
+        assert_eq!(ch.try_add(2), Err(Error::WouldBlock));
+        assert_eq!(ch.try_contains(&1), Err(Error::WouldBlock));
+        assert_eq!(ch.try_remove_one(&1), Err(Error::WouldBlock));
+        let start = Instant::now();
+        assert_eq!(ch.add_timeout(2, Duration::from_millis(20)), Err(Error::WouldBlock));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        drop(tables);
+        // the read guard of iter lets lookups through but not adds
+        let guard = ch.iter();
+        assert_eq!(ch.try_contains(&1), Ok(true));
+        assert_eq!(ch.try_add(1), Err(Error::WouldBlock));
+        assert_eq!(ch.try_add_n(2, 0), Ok(AddOutcome::Incremented));
+        drop(guard);
+        assert_eq!(ch.try_add(1), Ok(AddOutcome::Incremented));
+        assert_eq!(ch.total(), 2);
+        assert!(ch.stats().contention.writes >= 1);
+    }
+
+    #[test]
+    fn add_timeout() {
+        let ch = Arc::new(CHash::new());
+        let locked = Arc::new(std::sync::Barrier::new(2));
+        let holder = {
+            let (ch, locked) = (ch.clone(), locked.clone());
+            spawn(move || {
+                let tables = ch.table.write().unwrap();
+                locked.wait();
+                std::thread::sleep(Duration::from_millis(20));
+                drop(tables);
+            })
+        };
+        locked.wait();
+        assert_eq!(ch.try_add(1u32), Err(Error::WouldBlock));
+        assert_eq!(ch.add_timeout(1, Duration::from_secs(10)), Ok(AddOutcome::Inserted));
+        holder.join().unwrap();
+        assert_eq!(ch.add_timeout(1, Duration::MAX), Ok(AddOutcome::Incremented));
+        assert_eq!(ch.count(&1), 2);
+    }
+
+    #[test]
+    fn try_during_resize() {
+        let ch = CHash::new();
+        let mut i = 0;
+        while ch.size() < 64 {
+            ch.add(i);
+            i += 1;
+        }
+        assert!(ch.table.read().unwrap().old.is_some());
+        // the probe chain of the value is moved, not the chunks
+        assert_eq!(ch.try_contains(&0), Ok(true));
+        assert_eq!(ch.try_add(i), Ok(AddOutcome::Inserted));
+        assert!(ch.table.read().unwrap().old.is_some());
+        // a copy needs the resize to be over
+        assert_eq!(ch.try_snapshot().map(|pairs| pairs.len()), Err(Error::WouldBlock));
+        assert_eq!(ch.snapshot().len(), i as usize + 1);
+        assert_eq!(ch.try_snapshot().map(|pairs| pairs.len()), Ok(i as usize + 1));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Error::Poisoned.to_string(), "a lock was poisoned by a panicking thread");
+        assert_eq!(Error::CapacityExceeded.to_string(), "the table cannot grow any further");
+        assert_eq!(Error::ReservedValue.to_string(), "the value is reserved by the storage and cannot be added");
+        let boxed: Box<dyn std::error::Error> = Box::new(Error::WouldBlock);
+        assert_eq!(boxed.to_string(), "the lock is held by another thread");
+    }
+}
+
+// tests différentiels : des suites d'opérations tirées au hasard par proptest sont jouées
+// sur un CHash et sur une HashMap<T, usize> qui sert de modèle
+#[cfg(all(test, not(loom)))]
+mod proptests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    type Model = HashMap<u8, usize>;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Add(u8),
+        AddN(u8, usize),
+        Contains(u8),
+        Count(u8),
+        Remove(u8),
+        RemoveOne(u8),
+        Snapshot,
+        ShrinkToFit,
+        Clear,
+    }
+
+    /// few distinct values, so that they are added again and removed while present
+    fn value() -> impl Strategy<Value = u8> {
+        0..40u8
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            16 => value().prop_map(Op::Add),
+            8 => (value(), 0..4usize).prop_map(|(v,n)| Op::AddN(v, n)),
+            8 => value().prop_map(Op::Contains),
+            8 => value().prop_map(Op::Count),
+            4 => value().prop_map(Op::Remove),
+            8 => value().prop_map(Op::RemoveOne),
+            4 => Just(Op::Snapshot),
+            2 => Just(Op::ShrinkToFit),
+            1 => Just(Op::Clear),
+        ]
+    }
+
+    fn sorted(mut pairs: Vec<(u8, usize)>) -> Vec<(u8, usize)> {
+        pairs.sort();
+        pairs
+    }
+
+    /// plays op on the CHash and on the model, the results must be the same
+    fn apply<B: Slots<u8>>(ch: &CHash<u8, RandomState, B>, model: &mut Model, op: &Op) -> Result<(), TestCaseError> {
+        match *op {
+            Op::Add(v) => {
+                let expected = if model.contains_key(&v) { AddOutcome::Incremented } else { AddOutcome::Inserted };
+                *model.entry(v).or_insert(0) += 1;
+                prop_assert_eq!(ch.add(v), expected);
+            }
+            Op::AddN(v,n) => {
+                // 0 repetitions change nothing and report Incremented
+                let expected = if n > 0 && !model.contains_key(&v) { AddOutcome::Inserted } else { AddOutcome::Incremented };
+                if n > 0 {
+                    *model.entry(v).or_insert(0) += n;
+                }
+                prop_assert_eq!(ch.add_n(v, n), expected);
+            }
+            Op::Contains(v) => prop_assert_eq!(ch.contains(&v), model.contains_key(&v)),
+            Op::Count(v) => prop_assert_eq!(ch.count(&v), model.get(&v).copied().unwrap_or(0)),
+            Op::Remove(v) => prop_assert_eq!(ch.remove(&v), model.remove(&v).is_some()),
+            Op::RemoveOne(v) => {
+                let left = model.get_mut(&v).map(|r| {
+                    *r -= 1;
+                    *r
+                });
+                if left == Some(0) {
+                    model.remove(&v);
+                }
+                prop_assert_eq!(ch.remove_one(&v), left);
+            }
+            Op::Snapshot => prop_assert_eq!(sorted(ch.snapshot()), sorted(model.clone().into_iter().collect())),
+            Op::ShrinkToFit => {
+                ch.shrink_to_fit();
+                prop_assert_eq!(ch.size(), size_for(model.len(), ch.max_load_factor()));
+            }
+            Op::Clear => {
+                ch.clear();
+                model.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// what is left in the CHash once the operations are done, and what iteratortable yields
+    fn same_contents<B: Slots<u8>>(ch: CHash<u8, RandomState, B>, model: &Model) -> Result<(), TestCaseError> {
+        prop_assert_eq!(ch.total(), model.values().sum::<usize>());
+        prop_assert_eq!(ch.stats().distinct, model.len());
+        prop_assert_eq!(sorted(ch.snapshot()), sorted(model.clone().into_iter().collect()));
+        // every value exactly count times
+        let mut yielded = Model::new();
+        for v in ch.iteratortable() {
+            *yielded.entry(v).or_insert(0) += 1;
+        }
+        prop_assert_eq!(&yielded, model);
+        Ok(())
+    }
+
+    fn sequential<B: Slots<u8>>(ops: &[Op], min_load_factor: f64) -> Result<(), TestCaseError> {
+        let mut ch: CHash<u8, RandomState, B> = CHash::with_hasher(RandomState::new());
+        ch.set_min_load_factor(min_load_factor);
+        let mut model = Model::new();
+        for op in ops {
+            apply(&ch, &mut model, op)?;
+        }
+        same_contents(ch, &model)
+    }
+
+    /// every thread adds its own list, the adds commute so the model is their sum
+    fn threaded<B: Slots<u8> + Send + Sync + 'static>(adds: &[Vec<(u8, usize)>]) -> Result<(), TestCaseError> {
+        let ch: Arc<CHash<u8, RandomState, B>> = Arc::new(CHash::with_hasher(RandomState::new()));
+        let handles: Vec<_> = adds
+            .iter()
+            .cloned()
+            .map(|adds| {
+                let ch = ch.clone();
+                spawn(move || adds.into_iter().all(|(v,n)| {
+                    ch.add_n(v, n);
+                    ch.contains(&v)
+                }))
+            })
+            .collect();
+        for h in handles {
+            // nothing is removed, a value stays visible once added
+            prop_assert!(h.join().unwrap());
+        }
+        let mut model = Model::new();
+        for &(v,n) in adds.iter().flatten() {
+            *model.entry(v).or_insert(0) += n;
+        }
+        let ch = Arc::try_unwrap(ch).unwrap_or_else(|_| unreachable!("the threads were joined"));
+        same_contents(ch, &model)
+    }
+
+    fn thread_adds() -> impl Strategy<Value = Vec<Vec<(u8, usize)>>> {
+        prop::collection::vec(prop::collection::vec((value(), 1..4usize), 0..100), 1..5)
+    }
+
+    proptest! {
+        #[test]
+        fn sequential_locked(ops in prop::collection::vec(op(), 0..200)) {
+            sequential::<LockedSlots<u8>>(&ops, 0.0)?;
+        }
+
+        #[test]
+        fn sequential_atomic(ops in prop::collection::vec(op(), 0..200)) {
+            sequential::<AtomicSlots<u8>>(&ops, 0.0)?;
+        }
+
+        #[test]
+        fn sequential_robin_hood(ops in prop::collection::vec(op(), 0..200)) {
+            sequential::<RobinHoodSlots<u8>>(&ops, 0.0)?;
+        }
+
+        /// the removals shrink the table too
+        #[test]
+        fn sequential_low_water_mark(ops in prop::collection::vec(op(), 0..200)) {
+            sequential::<LockedSlots<u8>>(&ops, 0.3)?;
+        }
+
+        #[test]
+        fn threaded_locked(adds in thread_adds()) {
+            threaded::<LockedSlots<u8>>(&adds)?;
+        }
+
+        #[test]
+        fn threaded_atomic(adds in thread_adds()) {
+            threaded::<AtomicSlots<u8>>(&adds)?;
+        }
+
+        #[test]
+        fn threaded_robin_hood(adds in thread_adds()) {
+            threaded::<RobinHoodSlots<u8>>(&adds)?;
+        }
+
+        /// empty pairs, pairs with no repetition first or last
+        #[test]
+        fn iteratortable_pairs(pairs in prop::collection::vec((any::<u8>(), 0..4usize), 0..10)) {
+            let expected: Vec<_> = pairs.iter().flat_map(|&(v,r)| std::iter::repeat_n(v, r)).collect();
+            prop_assert_eq!(IteratorTable::new(pairs).collect::<Vec<_>>(), expected);
+        }
+
+        /// a table of 8 Containers holding up to 6 values, often in the last one
+        #[test]
+        fn iteratortable_table(values in prop::collection::vec(0..16u8, 0..7)) {
+            let table: Table<u8> = Table::new(8, RandomState::new());
+            let mut model = Model::new();
+            for &v in &values {
+                table.add(v,1).unwrap();
+                *model.entry(v).or_insert(0) += 1;
+            }
+            let mut yielded = Model::new();
+            for v in table.iteratortable() {
+                *yielded.entry(v).or_insert(0) += 1;
+            }
+            prop_assert_eq!(yielded, model);
+        }
+    }
+}
+
+// model checking avec loom : RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+
+    // the same hashes in every execution, loom replays them
+    type Deterministic = BuildHasherDefault<DefaultHasher>;
+
+    /// explores the interleavings with at most 3 preemptions, enough for races between two threads
+    fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(f);
+    }
+
+    /// a CHash of 4 Containers, full after 2 values
+    fn with_values<B: Slots<u32>>(values: &[u32]) -> CHash<u32, Deterministic, B> {
+        let ch = CHash::with_hasher(Deterministic::default());
+        for &v in values {
+            ch.add(v);
+        }
+        ch
+    }
+
+    #[test]
+    fn loom_add_racing_bigger() {
+        model(|| {
+            let ch = Arc::new(with_values::<LockedSlots<u32>>(&[1]));
+            let handles: Vec<_> = [2, 3]
+                .iter()
+                .map(|&v| {
+                    let ch = ch.clone();
+                    thread::spawn(move || assert_eq!(ch.add(v), AddOutcome::Inserted))
+                })
+                .collect();
+            for h in handles {
+                h.join().unwrap();
+            }
+            // one of the adds found the table full and doubled it
+            assert_eq!(ch.size(), 8);
+            for v in 1..4 {
+                assert_eq!(ch.count(&v), 1);
+            }
+            assert_eq!(ch.total(), 3);
+        });
+    }
+
+    fn same_value<B: Slots<u32> + Send + Sync + 'static>() {
+        model(|| {
+            let ch = Arc::new(with_values::<B>(&[]));
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let ch = ch.clone();
+                    thread::spawn(move || ch.add(7))
+                })
+                .collect();
+            let outcomes: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            // exactly one of the adds stored the value
+            assert_eq!(outcomes.iter().filter(|&&o| o == AddOutcome::Inserted).count(), 1);
+            assert_eq!(ch.count(&7), 2);
+            assert_eq!(ch.total(), 2);
+            assert_eq!(ch.snapshot(), vec![(7, 2)]);
+        });
+    }
+
+    #[test]
+    fn loom_add_same_value() {
+        same_value::<LockedSlots<u32>>();
+    }
+
+    #[test]
+    fn loom_add_same_value_atomic() {
+        same_value::<AtomicSlots<u32>>();
+    }
+
+    #[test]
+    fn loom_contains_during_double() {
+        model(|| {
+            let ch = Arc::new(with_values::<LockedSlots<u32>>(&[1, 2]));
+            let adder = {
+                let ch = ch.clone();
+                thread::spawn(move || {
+                    ch.add(3);
+                })
+            };
+            // 1 and 2 are seen in one of the two tables whatever the progress of the migration
+            assert!(ch.contains(&1));
+            assert!(ch.contains(&2));
+            adder.join().unwrap();
+            assert!(ch.contains(&3));
+            assert_eq!(ch.total(), 3);
+        });
     }
 }
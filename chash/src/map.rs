@@ -0,0 +1,760 @@
+// version clé -> valeur de CHash, même table à verrous par case
+use crate::guard::Held;
+use crate::sync::{AtomicUsize, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(loom)]
+use crate::sync::Poison;
+use crate::{max_used, unwrap, Error, Lock, PoisonPolicy, DEFAULT_MAX_LOAD_FACTOR};
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::Ordering;
+use std::sync::{PoisonError, TryLockError};
+
+#[derive(Debug)]
+enum Bucket<K, V> {
+    Empty,
+    /// a removed pair, kept so that keys further in the probe chain are still found
+    Removed,
+    Contains(K, V),
+}
+
+#[derive(Debug)]
+struct Table<K, V> {
+    buckets: Vec<RwLock<Bucket<K, V>>>,
+    size: usize,
+    hasher: RandomState,
+    /// number of rebuilds so far, see CHashMap::bigger
+    resizes: usize,
+}
+
+impl<K, V> Table<K, V> {
+    fn new(size: usize, resizes: usize) -> Self {
+        let buckets = (0..size).map(|_| RwLock::new(Bucket::Empty)).collect();
+        Table { buckets, size, hasher: RandomState::new(), resizes }
+    }
+
+    fn home<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        (self.hasher.hash_one(key) as usize) % self.size
+    }
+
+    /// read lock on the bucket holding key, if any
+    fn lookup<Q>(&self, key: &Q, lock: Lock) -> Result<Option<RwLockReadGuard<'_, Bucket<K, V>>>, Error>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        let mut hash = self.home(key);
+        for _ in 0..self.size {
+            let bucket = lock.read(&self.buckets[hash])?;
+            match &*bucket {
+                Bucket::Empty => return Ok(None),
+                Bucket::Contains(k, _) if k.borrow() == key => return Ok(Some(bucket)),
+                _ => hash = (hash + 1) % self.size,
+            }
+        }
+        Ok(None)
+    }
+
+    /// write lock on the bucket holding key, if any
+    fn lookup_mut<Q>(&self, key: &Q, lock: Lock) -> Result<Option<RwLockWriteGuard<'_, Bucket<K, V>>>, Error>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        let mut hash = self.home(key);
+        for _ in 0..self.size {
+            let bucket = lock.write(&self.buckets[hash])?;
+            match &*bucket {
+                Bucket::Empty => return Ok(None),
+                Bucket::Contains(k, _) if k.borrow() == key => return Ok(Some(bucket)),
+                _ => hash = (hash + 1) % self.size,
+            }
+        }
+        Ok(None)
+    }
+
+    /// write lock on the bucket holding key, or on the first empty one of its probe chain.
+    /// the caller must have reserved an empty bucket, see CHashMap::reserve.
+    /// removed buckets are not reused : another thread may be probing past them for the same key
+    fn lookup_or_free(&self, key: &K, lock: Lock) -> Result<RwLockWriteGuard<'_, Bucket<K, V>>, Error>
+        where K: Hash + Eq
+    {
+        let mut hash = self.home(key);
+        for _ in 0..self.size {
+            let bucket = lock.write(&self.buckets[hash])?;
+            match &*bucket {
+                Bucket::Empty => return Ok(bucket),
+                Bucket::Contains(k, _) if k == key => return Ok(bucket),
+                _ => hash = (hash + 1) % self.size,
+            }
+        }
+        panic!("no empty bucket left, CHashMap keeps some free");
+    }
+
+    /// removed buckets are purged here. the size is kept while the keys take at most half of
+    /// what it allows, so that the rebuilds stay amortized, and doubled otherwise.
+    /// returns the number of keys
+    fn rebuild(&mut self) -> Result<usize, Error>
+        where K: Hash + Eq
+    {
+        if self.buckets.iter().any(|b| b.is_poisoned()) {
+            return Err(Error::Poisoned);
+        }
+        let len = self.len(Lock::Wait)?;
+        let buckets = std::mem::take(&mut self.buckets);
+        let size = if len <= max_used(self.size, DEFAULT_MAX_LOAD_FACTOR) / 2 { self.size } else { 2 * self.size };
+        *self = Table::new(size, self.resizes + 1);
+        for bucket in buckets {
+            if let Bucket::Contains(k, v) = bucket.into_inner().unwrap_or_else(PoisonError::into_inner) {
+                let mut free = self.lookup_or_free(&k, Lock::Wait)?;
+                *free = Bucket::Contains(k, v);
+            }
+        }
+        Ok(len)
+    }
+
+    fn len(&self, lock: Lock) -> Result<usize, Error> {
+        let mut len = 0;
+        for bucket in &self.buckets {
+            if let Bucket::Contains(..) = &*lock.read(bucket)? {
+                len += 1;
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// an empty bucket reserved by an insertion, given back when dropped unless it was filled
+struct Reservation<'a>(&'a AtomicUsize);
+
+impl Reservation<'_> {
+    fn filled(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// a write locked bucket and the empty bucket reserved to fill it, if it is not filled yet
+struct BucketMut<'a, K, V> {
+    bucket: RwLockWriteGuard<'a, Bucket<K, V>>,
+    reservation: Option<Reservation<'a>>,
+}
+
+/// concurrent hash map with one RwLock per bucket, like CHash but storing a value for each key
+///
+/// guards returned by get, get_mut and entry hold the table lock : inserting from the thread
+/// holding one may deadlock if the table has to grow
+///
+/// the try_ methods never wait for a lock held by another thread and return an Error instead of
+/// panicking when a lock was poisoned by a panicking thread, see PoisonPolicy
+#[derive(Debug)]
+pub struct CHashMap<K, V> {
+    table: RwLock<Table<K, V>>,
+    /// empty buckets that can still be used before resizing, each insertion reserves one before
+    /// probing. removed ones are only given back by a resize
+    remaining: AtomicUsize,
+    poison_policy: PoisonPolicy,
+}
+
+impl<K, V> CHashMap<K, V> {
+    pub fn new() -> Self {
+        CHashMap {
+            table: RwLock::new(Table::new(4, 0)),
+            remaining: AtomicUsize::new(max_used(4, DEFAULT_MAX_LOAD_FACTOR)),
+            poison_policy: PoisonPolicy::default(),
+        }
+    }
+
+    /// number of buckets
+    pub fn size(&self) -> usize {
+        unwrap(self.recovering(Lock::Wait, || Ok(Lock::Wait.read(&self.table)?.size)))
+    }
+
+    /// number of keys, walks the whole table
+    pub fn len(&self) -> usize {
+        unwrap(self.recovering(Lock::Wait, || Lock::Wait.read(&self.table)?.len(Lock::Wait)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn poison_policy(&self) -> PoisonPolicy {
+        self.poison_policy
+    }
+
+    /// what to do with the locks poisoned by a panicking thread, PoisonPolicy::Propagate by default.
+    /// with PoisonPolicy::Clear the keys whose bucket was poisoned are removed
+    pub fn set_poison_policy(&mut self, poison_policy: PoisonPolicy) {
+        self.poison_policy = poison_policy;
+    }
+
+    /// runs f again once the poisoned locks are cleared, if the policy allows it
+    fn recovering<R, F>(&self, lock: Lock, mut f: F) -> Result<R, Error>
+        where F: FnMut() -> Result<R, Error>
+    {
+        loop {
+            match f() {
+                Err(Error::Poisoned) if self.poison_policy == PoisonPolicy::Clear => self.recover(lock)?,
+                result => return result,
+            }
+        }
+    }
+
+    /// the poisoned buckets become removed ones and the empty buckets left are counted again
+    fn recover(&self, lock: Lock) -> Result<(), Error> {
+        let mut table = match lock {
+            Lock::Wait => self.table.write().unwrap_or_else(PoisonError::into_inner),
+            Lock::Try => match self.table.try_write() {
+                Ok(table) => table,
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => return Err(Error::WouldBlock),
+            },
+        };
+        self.table.clear_poison();
+        let mut used = 0;
+        for bucket in &mut table.buckets {
+            if bucket.is_poisoned() {
+                *bucket.get_mut().unwrap_or_else(PoisonError::into_inner) = Bucket::Removed;
+                bucket.clear_poison();
+            }
+            if !matches!(*bucket.get_mut().unwrap_or_else(PoisonError::into_inner), Bucket::Empty) {
+                used += 1;
+            }
+        }
+        // no bucket is reserved, they are only while the table is read locked
+        self.remaining.store(max_used(table.size, DEFAULT_MAX_LOAD_FACTOR).saturating_sub(used), Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// read lock on the table once an empty bucket is reserved, growing it if needed.
+    /// the reservation is made under the read lock so that it cannot straddle a resize
+    fn reserve(&self, lock: Lock) -> Result<(RwLockReadGuard<'_, Table<K, V>>, Reservation<'_>), Error>
+        where K: Hash + Eq
+    {
+        loop {
+            let table = lock.read(&self.table)?;
+            if self.remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |r| r.checked_sub(1)).is_ok() {
+                return Ok((table, Reservation(&self.remaining)));
+            }
+            let resizes = table.resizes;
+            drop(table);
+            self.bigger(resizes, lock)?;
+        }
+    }
+
+    /// rebuilds the table, doubled unless removed buckets took the room. nothing is done if it was
+    /// resized since resizes was read, every thread that saw it full waits here but only the first
+    /// one resizes
+    fn bigger(&self, resizes: usize, lock: Lock) -> Result<(), Error>
+        where K: Hash + Eq
+    {
+        let mut table = lock.write(&self.table)?;
+        if table.resizes != resizes {
+            return Ok(());
+        }
+        if table.size > isize::MAX as usize / 2 / std::mem::size_of::<RwLock<Bucket<K, V>>>() {
+            return Err(Error::CapacityExceeded);
+        }
+        let len = table.rebuild()?;
+        self.remaining.store(max_used(table.size, DEFAULT_MAX_LOAD_FACTOR).saturating_sub(len), Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// write lock on the bucket of key, or on the empty one it would go in along with its reservation
+    fn locate(&self, key: &K, lock: Lock) -> Result<WriteGuard<'_, K, V>, Error>
+        where K: Hash + Eq
+    {
+        let (table, reservation) = self.reserve(lock)?;
+        let held = Held::new(table, |table| {
+            let bucket = table.lookup_or_free(key, lock)?;
+            // an insertion in a bucket already holding key gives its reservation back
+            let reservation = if let Bucket::Contains(..) = &*bucket { None } else { Some(reservation) };
+            Ok(Some(BucketMut { bucket, reservation }))
+        })?;
+        Ok(WriteGuard { held: held.expect("locate always finds a bucket") })
+    }
+
+    /// returns the previous value of key
+    pub fn insert(&self, key: K, value: V) -> Option<V>
+        where K: Hash + Eq
+    {
+        unwrap(self.insert_with(key, value, Lock::Wait))
+    }
+
+    /// see insert, the pair is dropped when an error is returned
+    pub fn try_insert(&self, key: K, value: V) -> Result<Option<V>, Error>
+        where K: Hash + Eq
+    {
+        self.insert_with(key, value, Lock::Try)
+    }
+
+    fn insert_with(&self, key: K, value: V, lock: Lock) -> Result<Option<V>, Error>
+        where K: Hash + Eq
+    {
+        let mut guard = self.recovering(lock, || self.locate(&key, lock))?;
+        if let Some(reservation) = guard.held.reservation.take() {
+            reservation.filled();
+        }
+        Ok(match std::mem::replace(&mut *guard.held.bucket, Bucket::Contains(key, value)) {
+            Bucket::Contains(_, old) => Some(old),
+            _ => None,
+        })
+    }
+
+    /// key can be any borrowed form of K, like for std::collections::HashMap
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        unwrap(self.recovering(Lock::Wait, || Ok(Lock::Wait.read(&self.table)?.lookup(key, Lock::Wait)?.is_some())))
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<ReadGuard<'_, K, V>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        unwrap(self.get_with(key, Lock::Wait))
+    }
+
+    /// see get
+    pub fn try_get<Q>(&self, key: &Q) -> Result<Option<ReadGuard<'_, K, V>>, Error>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        self.get_with(key, Lock::Try)
+    }
+
+    fn get_with<Q>(&self, key: &Q, lock: Lock) -> Result<Option<ReadGuard<'_, K, V>>, Error>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        self.recovering(lock, || {
+            let held = Held::new(lock.read(&self.table)?, |table| table.lookup(key, lock))?;
+            Ok(held.map(|held| ReadGuard { held }))
+        })
+    }
+
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<WriteGuard<'_, K, V>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        unwrap(self.get_mut_with(key, Lock::Wait))
+    }
+
+    /// see get_mut
+    pub fn try_get_mut<Q>(&self, key: &Q) -> Result<Option<WriteGuard<'_, K, V>>, Error>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        self.get_mut_with(key, Lock::Try)
+    }
+
+    fn get_mut_with<Q>(&self, key: &Q, lock: Lock) -> Result<Option<WriteGuard<'_, K, V>>, Error>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        self.recovering(lock, || {
+            let held = Held::new(lock.read(&self.table)?, |table| {
+                Ok(table.lookup_mut(key, lock)?.map(|bucket| BucketMut { bucket, reservation: None }))
+            })?;
+            Ok(held.map(|held| WriteGuard { held }))
+        })
+    }
+
+    /// returns the value that was stored for key
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        unwrap(self.try_remove_with(key, Lock::Wait))
+    }
+
+    /// see remove
+    pub fn try_remove<Q>(&self, key: &Q) -> Result<Option<V>, Error>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        self.try_remove_with(key, Lock::Try)
+    }
+
+    fn try_remove_with<Q>(&self, key: &Q, lock: Lock) -> Result<Option<V>, Error>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        self.recovering(lock, || {
+            let table = lock.read(&self.table)?;
+            let mut bucket = match table.lookup_mut(key, lock)? {
+                Some(bucket) => bucket,
+                None => return Ok(None),
+            };
+            match std::mem::replace(&mut *bucket, Bucket::Removed) {
+                Bucket::Contains(_, v) => Ok(Some(v)),
+                _ => unreachable!(),
+            }
+        })
+    }
+
+    /// the bucket of key stays write locked until the entry is dropped
+    pub fn entry(&self, key: K) -> Entry<'_, K, V>
+        where K: Hash + Eq
+    {
+        unwrap(self.entry_with(key, Lock::Wait))
+    }
+
+    /// see entry, the key is dropped when an error is returned
+    pub fn try_entry(&self, key: K) -> Result<Entry<'_, K, V>, Error>
+        where K: Hash + Eq
+    {
+        self.entry_with(key, Lock::Try)
+    }
+
+    fn entry_with(&self, key: K, lock: Lock) -> Result<Entry<'_, K, V>, Error>
+        where K: Hash + Eq
+    {
+        let guard = self.recovering(lock, || self.locate(&key, lock))?;
+        Ok(match &*guard.held.bucket {
+            Bucket::Contains(..) => Entry::Occupied(OccupiedEntry { guard }),
+            _ => Entry::Vacant(VacantEntry { guard, key }),
+        })
+    }
+}
+
+impl<K, V> Default for CHashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// read access to a value, the bucket stays read locked while it is alive
+pub struct ReadGuard<'a, K, V> {
+    held: Held<'a, Table<K, V>, RwLockReadGuard<'a, Bucket<K, V>>>,
+}
+
+impl<K, V> ReadGuard<'_, K, V> {
+    pub fn key(&self) -> &K {
+        match &**self.held {
+            Bucket::Contains(k, _) => k,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<K, V> Deref for ReadGuard<'_, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        match &**self.held {
+            Bucket::Contains(_, v) => v,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// write access to a value, the bucket stays write locked while it is alive
+pub struct WriteGuard<'a, K, V> {
+    held: Held<'a, Table<K, V>, BucketMut<'a, K, V>>,
+}
+
+impl<K, V> WriteGuard<'_, K, V> {
+    pub fn key(&self) -> &K {
+        match &*self.held.bucket {
+            Bucket::Contains(k, _) => k,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<K, V> Deref for WriteGuard<'_, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        match &*self.held.bucket {
+            Bucket::Contains(_, v) => v,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<K, V> DerefMut for WriteGuard<'_, K, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        match &mut *self.held.bucket {
+            Bucket::Contains(_, v) => v,
+            _ => unreachable!(),
+        }
+    }
+}
+
+pub enum Entry<'a, K, V> {
+    Occupied(OccupiedEntry<'a, K, V>),
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+impl<'a, K, V> Entry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(e) => e.guard.key(),
+            Entry::Vacant(e) => &e.key,
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> WriteGuard<'a, K, V> {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> WriteGuard<'a, K, V> {
+        match self {
+            Entry::Occupied(e) => e.guard,
+            Entry::Vacant(e) => e.insert(default()),
+        }
+    }
+
+    pub fn or_default(self) -> WriteGuard<'a, K, V>
+        where V: Default
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut e) => {
+                f(&mut e.guard);
+                Entry::Occupied(e)
+            }
+            vacant => vacant,
+        }
+    }
+}
+
+pub struct OccupiedEntry<'a, K, V> {
+    guard: WriteGuard<'a, K, V>,
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V> {
+    pub fn get(&self) -> &V {
+        &self.guard
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.guard
+    }
+
+    pub fn into_mut(self) -> WriteGuard<'a, K, V> {
+        self.guard
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        std::mem::replace(&mut self.guard, value)
+    }
+
+    pub fn remove(mut self) -> V {
+        match std::mem::replace(&mut *self.guard.held.bucket, Bucket::Removed) {
+            Bucket::Contains(_, v) => v,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// holds the empty bucket reserved for key until it is filled or the entry is dropped
+pub struct VacantEntry<'a, K, V> {
+    guard: WriteGuard<'a, K, V>,
+    key: K,
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn insert(mut self, value: V) -> WriteGuard<'a, K, V> {
+        *self.guard.held.bucket = Bucket::Contains(self.key, value);
+        if let Some(reservation) = self.guard.held.reservation.take() {
+            reservation.filled();
+        }
+        self.guard
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    #[test]
+    fn insert_get() {
+        let map = CHashMap::new();
+        assert_eq!(map.insert(1, "un"), None);
+        assert_eq!(map.insert(2, "deux"), None);
+        assert_eq!(map.insert(1, "one"), Some("un"));
+        assert_eq!(*map.get(&1).unwrap(), "one");
+        assert!(map.get(&3).is_none());
+        *map.get_mut(&2).unwrap() = "two";
+        assert_eq!(*map.get(&2).unwrap(), "two");
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn remove_keeps_chain() {
+        let map = CHashMap::new();
+        for i in 0..100 {
+            map.insert(i, i * 10);
+        }
+        for i in (0..100).step_by(2) {
+            assert_eq!(map.remove(&i), Some(i * 10));
+        }
+        assert_eq!(map.remove(&0), None);
+        for i in 0..100 {
+            assert_eq!(map.get(&i).map(|v| *v), if i % 2 == 0 { None } else { Some(i * 10) });
+        }
+        assert_eq!(map.len(), 50);
+    }
+
+    #[test]
+    fn entry() {
+        let map = CHashMap::new();
+        map.entry("a").or_insert(1);
+        map.entry("a").and_modify(|v| *v += 1).or_insert(10);
+        map.entry("b").and_modify(|v| *v += 1).or_insert_with(|| 10);
+        assert_eq!(*map.get(&"a").unwrap(), 2);
+        assert_eq!(*map.get(&"b").unwrap(), 10);
+        if let Entry::Occupied(e) = map.entry("b") {
+            assert_eq!(e.remove(), 10);
+        }
+        assert!(!map.contains_key(&"b"));
+    }
+
+    #[test]
+    fn entry_threads() {
+        let map = Arc::new(CHashMap::new());
+        let handlers: Vec<_> = (0..4)
+            .map(|_| {
+                let map = map.clone();
+                spawn(move || {
+                    for i in 0..1000 {
+                        *map.entry(i % 10).or_insert(0) += 1;
+                    }
+                })
+            })
+            .collect();
+        for h in handlers {
+            h.join().unwrap();
+        }
+        for i in 0..10 {
+            assert_eq!(*map.get(&i).unwrap(), 400);
+        }
+    }
+
+    #[test]
+    fn insert_threads() {
+        // every thread used to pass the resize check at once and fill the table, lookups of
+        // missing keys then probed it forever
+        for _ in 0..50 {
+            let map = Arc::new(CHashMap::new());
+            let handlers: Vec<_> = (0..8)
+                .map(|t| {
+                    let map = map.clone();
+                    spawn(move || {
+                        for i in 0..200 {
+                            assert_eq!(map.insert(t * 1000 + i, i), None);
+                            assert!(!map.contains_key(&(t * 1000 + i + 500)));
+                        }
+                    })
+                })
+                .collect();
+            for h in handlers {
+                h.join().unwrap();
+            }
+            assert_eq!(map.len(), 1600);
+            assert!(map.len() <= map.size() * 3 / 4);
+            for t in 0..8 {
+                assert!((0..200).all(|i| map.contains_key(&(t * 1000 + i))));
+            }
+        }
+    }
+
+    #[test]
+    fn churn_keeps_size() {
+        // removed buckets used to be purged only by doubling the table, which then grew forever
+        let map = CHashMap::new();
+        for i in 0..100_000 {
+            map.insert(i % 3, i);
+            map.remove(&(i % 3));
+        }
+        assert!(map.size() <= 8);
+        for _ in 0..20 {
+            for i in 0..1000 {
+                map.insert(i, i);
+            }
+            for i in 0..1000 {
+                map.remove(&i);
+            }
+        }
+        assert!(map.is_empty());
+        assert!(map.size() <= 4096);
+    }
+
+    #[test]
+    fn vacant_entry_dropped() {
+        let map: CHashMap<i32, i32> = CHashMap::new();
+        // the buckets reserved by entries left vacant are given back, the table does not grow
+        for i in 0..100 {
+            drop(map.entry(i));
+        }
+        assert_eq!(map.size(), 4);
+        assert!(map.is_empty());
+    }
+
+    /// a map holding 1 and 2 whose bucket of 1 was poisoned by a panic while it was write locked
+    fn poisoned() -> CHashMap<i32, i32> {
+        let map = Arc::new(CHashMap::new());
+        map.insert(1, 10);
+        map.insert(2, 20);
+        let m = map.clone();
+        assert!(spawn(move || {
+            let _guard = m.get_mut(&1).unwrap();
+            panic!("poisons the bucket");
+        }).join().is_err());
+        Arc::try_unwrap(map).unwrap_or_else(|_| unreachable!("the thread is over"))
+    }
+
+    #[test]
+    fn poison_propagated() {
+        let map = poisoned();
+        assert_eq!(map.poison_policy(), PoisonPolicy::Propagate);
+        assert_eq!(map.try_get(&1).err(), Some(Error::Poisoned));
+        assert_eq!(map.try_insert(1, 11), Err(Error::Poisoned));
+        let map = Arc::new(map);
+        let m = map.clone();
+        assert!(spawn(move || m.contains_key(&1)).join().is_err());
+    }
+
+    #[test]
+    fn poison_cleared() {
+        let mut map = poisoned();
+        map.set_poison_policy(PoisonPolicy::Clear);
+        assert!(map.get(&1).is_none());
+        assert_eq!(*map.get(&2).unwrap(), 20);
+        assert_eq!(map.insert(1, 11), None);
+        assert_eq!(map.len(), 2);
+        for i in 3..100 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.len(), 99);
+    }
+
+    #[test]
+    fn try_would_block() {
+        let map = CHashMap::new();
+        map.insert(1, 10);
+        let guard = map.get_mut(&1).unwrap();
+        assert_eq!(map.try_get(&1).err(), Some(Error::WouldBlock));
+        assert_eq!(map.try_remove(&1), Err(Error::WouldBlock));
+        drop(guard);
+        assert_eq!(map.try_remove(&1), Ok(Some(10)));
+    }
+
+    #[test]
+    fn borrowed_keys() {
+        let map = CHashMap::new();
+        map.insert("un".to_string(), 1);
+        map.insert("deux".to_string(), 2);
+        assert!(map.contains_key("un"));
+        assert_eq!(*map.get("deux").unwrap(), 2);
+        *map.get_mut("un").unwrap() += 10;
+        assert_eq!(map.remove("un"), Some(11));
+        assert!(!map.contains_key("un"));
+    }
+}
@@ -0,0 +1,203 @@
+// interface commune des multiensembles partagés entre threads : le code qui compte passe
+// d'un comptage exact (CHash, ShardedCHash, une HashMap verrouillée) à un comptage approché
+// (CountMinSketch) sans changer, et la même suite de tests tourne sur chacun
+use crate::{CHash, ShardedCHash, Slots};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Mutex, PoisonError, RwLock};
+
+/// multiset shared between threads, exact like CHash or approximate like CountMinSketch
+pub trait ConcurrentMultiset<T> {
+    fn add(&self, value: T) {
+        self.add_n(value, 1)
+    }
+
+    fn add_n(&self, value: T, repetitions: usize);
+
+    /// number of repetitions of value, never below the exact one for the approximate multisets
+    fn count(&self, value: &T) -> usize;
+
+    /// false positives are possible for the approximate multisets
+    fn contains(&self, value: &T) -> bool {
+        self.count(value) > 0
+    }
+
+    /// sum of the repetitions of every value
+    fn total(&self) -> usize;
+}
+
+impl<T, S, B> ConcurrentMultiset<T> for CHash<T, S, B>
+    where T: Hash + Eq, S: BuildHasher + Clone, B: Slots<T>
+{
+    fn add_n(&self, value: T, repetitions: usize) {
+        CHash::add_n(self, value, repetitions);
+    }
+
+    fn count(&self, value: &T) -> usize {
+        CHash::count(self, value)
+    }
+
+    fn contains(&self, value: &T) -> bool {
+        CHash::contains(self, value)
+    }
+
+    fn total(&self) -> usize {
+        CHash::total(self)
+    }
+}
+
+impl<T, S, B> ConcurrentMultiset<T> for ShardedCHash<T, S, B>
+    where T: Hash + Eq, S: BuildHasher + Clone, B: Slots<T>
+{
+    fn add_n(&self, value: T, repetitions: usize) {
+        ShardedCHash::add_n(self, value, repetitions);
+    }
+
+    fn count(&self, value: &T) -> usize {
+        ShardedCHash::count(self, value)
+    }
+
+    fn contains(&self, value: &T) -> bool {
+        ShardedCHash::contains(self, value)
+    }
+
+    fn total(&self) -> usize {
+        ShardedCHash::total(self)
+    }
+}
+
+/// a HashMap behind a single lock, the simplest multiset to compare the others with.
+/// a lock poisoned by a panicking thread is taken anyway, each update is a single addition
+impl<T: Hash + Eq, S: BuildHasher> ConcurrentMultiset<T> for Mutex<HashMap<T, usize, S>> {
+    fn add_n(&self, value: T, repetitions: usize) {
+        if repetitions > 0 {
+            *self.lock().unwrap_or_else(PoisonError::into_inner).entry(value).or_insert(0) += repetitions;
+        }
+    }
+
+    fn count(&self, value: &T) -> usize {
+        self.lock().unwrap_or_else(PoisonError::into_inner).get(value).copied().unwrap_or(0)
+    }
+
+    fn total(&self) -> usize {
+        self.lock().unwrap_or_else(PoisonError::into_inner).values().sum()
+    }
+}
+
+/// see the Mutex one, lookups share the lock
+impl<T: Hash + Eq, S: BuildHasher> ConcurrentMultiset<T> for RwLock<HashMap<T, usize, S>> {
+    fn add_n(&self, value: T, repetitions: usize) {
+        if repetitions > 0 {
+            *self.write().unwrap_or_else(PoisonError::into_inner).entry(value).or_insert(0) += repetitions;
+        }
+    }
+
+    fn count(&self, value: &T) -> usize {
+        self.read().unwrap_or_else(PoisonError::into_inner).get(value).copied().unwrap_or(0)
+    }
+
+    fn total(&self) -> usize {
+        self.read().unwrap_or_else(PoisonError::into_inner).values().sum()
+    }
+}
+
+// la même suite pour chaque implémentation, les comptes approchés ne doivent jamais être
+// en dessous des comptes exacts
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::{AtomicCHash, AtomicSlots, CountMinSketch, RobinHoodCHash};
+    use std::collections::hash_map::RandomState;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    fn check_count<M: ConcurrentMultiset<u64>>(multiset: &M, value: u64, expected: usize, exact: bool) {
+        let count = multiset.count(&value);
+        if exact {
+            assert_eq!(count, expected, "count of {}", value);
+        } else {
+            assert!(count >= expected, "count of {} is {} below {}", value, count, expected);
+        }
+    }
+
+    fn sequential<M: ConcurrentMultiset<u64>>(multiset: &M, exact: bool) {
+        assert_eq!(multiset.total(), 0);
+        for i in 0..1000 {
+            multiset.add(i%100);
+        }
+        multiset.add_n(7, 5);
+        multiset.add_n(1000, 3);
+        multiset.add_n(1001, 0);
+        assert_eq!(multiset.total(), 1008);
+        check_count(multiset, 7, 15, exact);
+        check_count(multiset, 99, 10, exact);
+        check_count(multiset, 1000, 3, exact);
+        assert!(multiset.contains(&1000));
+        if exact {
+            assert!(!multiset.contains(&1001));
+            assert_eq!(multiset.count(&5000), 0);
+        }
+    }
+
+    fn threads<M: ConcurrentMultiset<u64> + Send + Sync + 'static>(multiset: M, exact: bool) {
+        let multiset = Arc::new(multiset);
+        let handles: Vec<_> = (0..4u64)
+            .map(|t| {
+                let multiset = multiset.clone();
+                spawn(move || {
+                    for i in 0..10_000 {
+                        multiset.add(i%1000 + t);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(multiset.total(), 40_000);
+        // 3 is added by every thread, 1002 only by the last ones
+        check_count(&*multiset, 3, 40, exact);
+        check_count(&*multiset, 1002, 10, exact);
+        check_count(&*multiset, 0, 10, exact);
+    }
+
+    macro_rules! suite {
+        ($($name:ident => $new:expr, $exact:expr;)*) => {$(
+            #[test]
+            fn $name() {
+                sequential(&$new, $exact);
+                threads($new, $exact);
+            }
+        )*};
+    }
+
+    suite! {
+        chash => CHash::<u64>::new(), true;
+        atomic_chash => AtomicCHash::<u64>::default(), true;
+        robin_hood_chash => RobinHoodCHash::<u64>::default(), true;
+        sharded_chash => ShardedCHash::<u64>::with_shards(4), true;
+        atomic_sharded_chash => ShardedCHash::<u64, RandomState, AtomicSlots<u64>>::default(), true;
+        mutex_hashmap => Mutex::new(HashMap::<u64, usize>::new()), true;
+        rwlock_hashmap => RwLock::new(HashMap::<u64, usize>::new()), true;
+        count_min_sketch => CountMinSketch::with_error(0.001, 0.01), false;
+    }
+
+    /// generic code only sees the trait, any implementation can be passed
+    fn most_frequent(multiset: &dyn ConcurrentMultiset<u64>, candidates: &[u64]) -> Option<u64> {
+        candidates.iter().copied().max_by_key(|v| multiset.count(v))
+    }
+
+    #[test]
+    fn trait_objects() {
+        let multisets: Vec<Box<dyn ConcurrentMultiset<u64>>> = vec![
+            Box::new(CHash::<u64>::new()),
+            Box::new(Mutex::new(HashMap::<u64, usize>::new())),
+            Box::new(CountMinSketch::new(1024, 4)),
+        ];
+        for multiset in &multisets {
+            multiset.add_n(2, 3);
+            multiset.add(5);
+            assert_eq!(most_frequent(&**multiset, &[1, 2, 5]), Some(2));
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// itérateurs parallèles rayon : parcours des Containers par tranches, et remplissage
+// d'un CHash depuis tous les threads du pool après avoir réservé la place une seule fois
+use crate::{unwrap, CHash, Lock, Slots};
+use rayon::iter::plumbing::UnindexedConsumer;
+use rayon::iter::{FromParallelIterator, IntoParallelIterator, ParallelExtend, ParallelIterator};
+use std::cmp::min;
+use std::hash::{BuildHasher, Hash};
+
+/// number of Containers copied by a task of ParIter
+const PAR_ITER_CHUNK: usize = 1024;
+
+/// parallel iterator over the (value, repetitions) pairs of a CHash, see CHash::par_iter
+pub struct ParIter<'a, T, S, B> {
+    ch: &'a CHash<T, S, B>,
+}
+
+impl<'a, T, S, B> ParallelIterator for ParIter<'a, T, S, B>
+    where T: Hash + Clone + Send + Sync, S: BuildHasher + Clone + Send + Sync, B: Slots<T> + Send + Sync
+{
+    type Item = (T, usize);
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        let tables = unwrap(self.ch.recovering(Lock::Wait, || self.ch.settled(Lock::Wait)));
+        let slots = &tables.current.slots;
+        let len = slots.len();
+        (0..len.div_ceil(PAR_ITER_CHUNK))
+            .into_par_iter()
+            .flat_map_iter(|chunk| {
+                let start = chunk*PAR_ITER_CHUNK;
+                let mut pairs = Vec::new();
+                unwrap(slots.for_each_in(start..min(start+PAR_ITER_CHUNK, len), Lock::Wait, |v,r| pairs.push((v.clone(), r))));
+                pairs
+            })
+            .drive_unindexed(consumer)
+    }
+}
+
+impl<T, S, B> CHash<T, S, B>
+    where T: Hash + Clone + Send + Sync, S: BuildHasher + Clone + Send + Sync, B: Slots<T> + Send + Sync
+{
+    /// copies the (value, repetitions) pairs from the rayon pool. the table stays read locked
+    /// while the iterator is driven, add and remove still run but a resize waits for it
+    pub fn par_iter(&self) -> ParIter<'_, T, S, B> {
+        ParIter { ch: self }
+    }
+}
+
+impl<'a, T, S, B> IntoParallelIterator for &'a CHash<T, S, B>
+    where T: Hash + Clone + Send + Sync, S: BuildHasher + Clone + Send + Sync, B: Slots<T> + Send + Sync
+{
+    type Iter = ParIter<'a, T, S, B>;
+    type Item = (T, usize);
+
+    fn into_par_iter(self) -> Self::Iter {
+        self.par_iter()
+    }
+}
+
+impl<T, S, B> ParallelExtend<T> for CHash<T, S, B>
+    where T: Hash + PartialEq + Send + Sync, S: BuildHasher + Clone + Send + Sync, B: Slots<T> + Send + Sync
+{
+    /// the table grows once for the length of the iterator when it is known, then every thread adds
+    fn par_extend<I: IntoParallelIterator<Item = T>>(&mut self, par_iter: I) {
+        let par_iter = par_iter.into_par_iter();
+        if let Some(len) = par_iter.opt_len() {
+            self.grow_for(len);
+        }
+        let ch = &*self;
+        par_iter.for_each(|v| {
+            ch.add(v);
+        });
+    }
+}
+
+impl<T, S, B> FromParallelIterator<T> for CHash<T, S, B>
+    where T: Hash + PartialEq + Send + Sync, S: BuildHasher + Clone + Default + Send + Sync, B: Slots<T> + Send + Sync
+{
+    fn from_par_iter<I: IntoParallelIterator<Item = T>>(par_iter: I) -> Self {
+        let mut ch = CHash::default();
+        ch.par_extend(par_iter);
+        ch
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{AtomicCHash, CHash};
+    use rayon::prelude::*;
+
+    #[test]
+    fn collect() {
+        // beaucoup without the hand spawned threads
+        let ch: CHash<u64> = (0..300_000u64).into_par_iter().map(|i| i%100_000).collect();
+        assert_eq!(ch.total(), 300_000);
+        assert_eq!(ch.count(&4242), 3);
+        // the length is known, the table grew once
+        assert_eq!(ch.stats().resizes, 1);
+        let atomic: AtomicCHash<u64> = (0..100_000u64).into_par_iter().collect();
+        assert!(atomic.contains(&99_999));
+    }
+
+    #[test]
+    fn par_iter() {
+        let ch: CHash<u32> = CHash::new();
+        for i in 0..10_000 {
+            ch.add_n(i, (i%3) as usize + 1);
+        }
+        assert_eq!(ch.par_iter().count(), 10_000);
+        assert_eq!(ch.par_iter().map(|(_,r)| r).sum::<usize>(), ch.total());
+        let mut pairs: Vec<_> = (&ch).into_par_iter().collect();
+        pairs.sort();
+        let mut expected = ch.snapshot();
+        expected.sort();
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn par_extend() {
+        let mut ch: CHash<String> = CHash::with_max_repetitions(2);
+        ch.add("a".to_string());
+        ch.par_extend((0..5000).into_par_iter().map(|i| format!("{}", i%1000)));
+        ch.par_extend(vec!["a".to_string(); 5]);
+        assert_eq!(ch.count("a"), 2);
+        assert_eq!(ch.count("999"), 2);
+        assert_eq!(ch.total(), 2002);
+    }
+}
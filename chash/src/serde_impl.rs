@@ -0,0 +1,126 @@
+// un CHash est sérialisé comme une map valeur -> nombre de répétitions
+use crate::snapshot::MAX_PRESIZE;
+use crate::{max_used, CHash, Lock, Slots, DEFAULT_MAX_LOAD_FACTOR};
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Error, Serialize, SerializeMap, Serializer};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+
+impl<T, S, B> Serialize for CHash<T, S, B>
+    where T: Serialize + Clone + Hash, S: BuildHasher + Clone, B: Slots<T>
+{
+    /// the pairs are copied first, the CHash is not locked while the serializer writes them and
+    /// formats that need the number of entries upfront get it
+    fn serialize<Se: Serializer>(&self, serializer: Se) -> Result<Se::Ok, Se::Error> {
+        let pairs = self.snapshot_with(Lock::Wait).map_err(Se::Error::custom)?;
+        let mut map = serializer.serialize_map(Some(pairs.len()))?;
+        for (v,r) in &pairs {
+            map.serialize_entry(v, r)?;
+        }
+        map.end()
+    }
+}
+
+struct CHashVisitor<T, S, B>(PhantomData<(T, S, B)>);
+
+impl<'de, T, S, B> Visitor<'de> for CHashVisitor<T, S, B>
+    where T: Deserialize<'de> + Hash, S: BuildHasher + Clone + Default, B: Slots<T>
+{
+    type Value = CHash<T, S, B>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map from values to their number of repetitions")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+        // the hint comes from the input, the table grows past MAX_PRESIZE as values are added
+        let capacity = access.size_hint().unwrap_or(0).min(max_used(MAX_PRESIZE, DEFAULT_MAX_LOAD_FACTOR));
+        let ch = CHash::with_capacity_and_hasher(capacity, S::default());
+        while let Some((value, repetitions)) = access.next_entry()? {
+            ch.add_n(value, repetitions);
+        }
+        Ok(ch)
+    }
+}
+
+impl<'de, T, S, B> Deserialize<'de> for CHash<T, S, B>
+    where T: Deserialize<'de> + Hash, S: BuildHasher + Clone + Default, B: Slots<T>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(CHashVisitor(PhantomData))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::AtomicCHash;
+    use serde::de::value::{self, MapDeserializer};
+    use std::collections::HashMap;
+
+    #[test]
+    fn json_roundtrip() {
+        let ch: CHash<String> = CHash::new();
+        for i in 0..500 {
+            ch.add(format!("w{}", i%50));
+        }
+        let json = serde_json::to_string(&ch).unwrap();
+        let counts: HashMap<String, usize> = serde_json::from_str(&json).unwrap();
+        assert_eq!(counts.len(), 50);
+        assert_eq!(counts["w7"], 10);
+        let restored: CHash<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.total(), 500);
+        assert_eq!(restored.count("w49"), 10);
+    }
+
+    #[test]
+    fn json_atomic() {
+        let ch: AtomicCHash<u32> = serde_json::from_str(r#"{"1": 3, "2": 4}"#).unwrap();
+        assert_eq!(ch.count(&1), 3);
+        assert_eq!(ch.total(), 7);
+        assert!(serde_json::from_str::<CHash<u32>>("[1, 2]").is_err());
+    }
+
+    #[test]
+    fn bincode_roundtrip() {
+        // bincode needs the length of the map before its entries
+        let ch: CHash<String> = CHash::new();
+        for i in 0..500 {
+            ch.add(format!("w{}", i%50));
+        }
+        let bytes = bincode::serialize(&ch).unwrap();
+        let restored: CHash<String> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(restored.total(), 500);
+        assert_eq!(restored.count("w49"), 10);
+        let atomic = AtomicCHash::<u64>::default();
+        for i in 0..100 {
+            atomic.add(i);
+        }
+        let restored: AtomicCHash<u64> = bincode::deserialize(&bincode::serialize(&atomic).unwrap()).unwrap();
+        assert_eq!(restored.snapshot().len(), 100);
+    }
+
+    /// two pairs announcing many more
+    struct Lying(std::vec::IntoIter<(u32, usize)>);
+
+    impl Iterator for Lying {
+        type Item = (u32, usize);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.0.next()
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (1 << 60, Some(1 << 60))
+        }
+    }
+
+    #[test]
+    fn size_hint_capped() {
+        let pairs = MapDeserializer::<_, value::Error>::new(Lying(vec![(1, 3), (2, 4)].into_iter()));
+        let ch = CHash::<u32>::deserialize(pairs).unwrap();
+        assert_eq!(ch.total(), 7);
+        assert_eq!(ch.size(), MAX_PRESIZE);
+    }
+}
@@ -0,0 +1,283 @@
+// plusieurs CHash indépendants, comme chashmap : les bits de poids fort du hash choisissent
+// le shard, les bits de poids faible la case dans sa table. un shard qui grandit ne bloque que lui
+use crate::{AddOutcome, CHash, DEFAULT_MAX_LOAD_FACTOR, LockedSlots, PoisonPolicy, Slots, TableRef};
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+
+/// multiset split between independent CHash shards, each one resized on its own
+#[derive(Debug)]
+pub struct ShardedCHash<T, S = RandomState, B = LockedSlots<T>> {
+    shards: Box<[CHash<T, S, B>]>,
+    hasher: S,
+    /// the shard of a value is its hash shifted right by shift
+    shift: u32,
+}
+
+/// four shards per thread of the machine
+fn default_shards() -> usize {
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    (threads*4).next_power_of_two()
+}
+
+impl<T: PartialEq> ShardedCHash<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// shards is rounded up to a power of two
+    pub fn with_shards(shards: usize) -> Self {
+        Self::with_shards_and_hasher(shards, RandomState::new())
+    }
+}
+
+impl<T, S: BuildHasher + Clone, B: Slots<T>> ShardedCHash<T, S, B> {
+    /// shards is rounded up to a power of two, every shard uses a clone of hasher
+    pub fn with_shards_and_hasher(shards: usize, hasher: S) -> Self {
+        Self::with_shards_capacity_and_hasher(shards, 0, hasher)
+    }
+
+    /// room for capacity values spread evenly between the shards
+    pub fn with_shards_capacity_and_hasher(shards: usize, capacity: usize, hasher: S) -> Self {
+        assert!(shards > 0, "a ShardedCHash needs at least one shard");
+        let shards = shards.next_power_of_two();
+        let per_shard = capacity.div_ceil(shards);
+        ShardedCHash {
+            shards: (0..shards)
+                .map(|_| CHash::with_capacity_max_load_factor_and_hasher(per_shard, DEFAULT_MAX_LOAD_FACTOR, hasher.clone()))
+                .collect(),
+            hasher,
+            shift: 64 - shards.trailing_zeros(),
+        }
+    }
+
+    /// bounds the number of repetitions of each value in every shard, see CHash::set_max_repetitions
+    pub fn set_max_repetitions(&mut self, max_repetitions: usize) {
+        for shard in self.shards.iter_mut() {
+            shard.set_max_repetitions(max_repetitions);
+        }
+    }
+
+    /// see CHash::set_poison_policy
+    pub fn set_poison_policy(&mut self, poison_policy: PoisonPolicy) {
+        for shard in self.shards.iter_mut() {
+            shard.set_poison_policy(poison_policy);
+        }
+    }
+
+    /// every shard shrinks on its own below the low-water mark, see CHash::set_min_load_factor
+    pub fn set_min_load_factor(&mut self, min_load_factor: f64) {
+        for shard in self.shards.iter_mut() {
+            shard.set_min_load_factor(min_load_factor);
+        }
+    }
+
+    pub fn shards(&self) -> &[CHash<T, S, B>] {
+        &self.shards
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, value: &Q) -> &CHash<T, S, B> {
+        let hash = self.hasher.hash_one(value);
+        &self.shards[hash.checked_shr(self.shift).unwrap_or(0) as usize]
+    }
+
+    pub fn add(&self, value: T) -> AddOutcome
+        where T: Hash
+    {
+        self.shard(&value).add(value)
+    }
+
+    pub fn add_n(&self, value: T, repetitions: usize) -> AddOutcome
+        where T: Hash
+    {
+        self.shard(&value).add_n(value, repetitions)
+    }
+
+    pub fn contains<Q>(&self, value: &Q) -> bool
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        self.shard(value).contains(value)
+    }
+
+    pub fn count<Q>(&self, value: &Q) -> usize
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        self.shard(value).count(value)
+    }
+
+    pub fn remove<Q>(&self, value: &Q) -> bool
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        self.shard(value).remove(value)
+    }
+
+    pub fn remove_one<Q>(&self, value: &Q) -> Option<usize>
+        where T: Borrow<Q> + Hash, Q: Hash + Eq + ?Sized
+    {
+        self.shard(value).remove_one(value)
+    }
+
+    /// sum of the repetitions of every value. the shards are read one after the other,
+    /// concurrent adds may be counted or not
+    pub fn total(&self) -> usize {
+        self.shards.iter().map(|shard| shard.total()).sum()
+    }
+
+    /// number of Containers of all the shards
+    pub fn size(&self) -> usize {
+        self.shards.iter().map(|shard| shard.size()).sum()
+    }
+
+    /// shrinks every shard, one after the other, see CHash::shrink_to_fit
+    pub fn shrink_to_fit(&self)
+        where T: Hash
+    {
+        for shard in self.shards.iter() {
+            shard.shrink_to_fit();
+        }
+    }
+
+    /// clears every shard, one after the other
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.clear();
+        }
+    }
+
+    /// see CHash::memory_usage
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() + self.shards.iter().map(|shard| shard.memory_usage()).sum::<usize>()
+    }
+
+    /// copies the (value, repetitions) pairs of every shard, one shard after the other
+    pub fn snapshot(&self) -> Vec<(T, usize)>
+        where T: Clone + Hash
+    {
+        self.shards.iter().flat_map(|shard| shard.snapshot()).collect()
+    }
+
+    /// the k values with the most repetitions, most repeated first. ties are broken arbitrarily
+    pub fn top_k(&self, k: usize) -> Vec<(T, usize)>
+        where T: Clone + Hash
+    {
+        let mut best: Vec<_> = self.shards.iter().flat_map(|shard| shard.top_k(k)).collect();
+        best.sort_by_key(|&(_,r)| Reverse(r));
+        best.truncate(k);
+        best
+    }
+
+    /// the values of every shard, each shard finishing its resize first
+    pub fn iteratortable(self) -> impl Iterator<Item = T>
+        where T: Hash + Copy
+    {
+        self.shards.into_vec().into_iter().flat_map(|shard| shard.iteratortable())
+    }
+}
+
+impl<T: PartialEq + Hash, S: BuildHasher + Clone> ShardedCHash<T, S, LockedSlots<T>> {
+    /// read locks every shard until the returned guard is dropped, see CHash::iter
+    pub fn iter(&self) -> ShardsRef<'_, T, S> {
+        ShardsRef { shards: self.shards.iter().map(|shard| shard.iter()).collect() }
+    }
+}
+
+impl<T, S: BuildHasher + Clone + Default, B: Slots<T>> Default for ShardedCHash<T, S, B> {
+    fn default() -> Self {
+        Self::with_shards_and_hasher(default_shards(), S::default())
+    }
+}
+
+/// read guard over every shard of a ShardedCHash, see ShardedCHash::iter
+pub struct ShardsRef<'a, T, S = RandomState> {
+    shards: Vec<TableRef<'a, T, S>>,
+}
+
+impl<T, S> ShardsRef<'_, T, S> {
+    /// (value, repetitions) pairs
+    pub fn iter(&self) -> impl Iterator<Item = (&T, usize)> + '_ {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    /// each value repeated as many times as it was added
+    pub fn iter_expanded(&self) -> impl Iterator<Item = T> + '_
+        where T: Clone
+    {
+        self.shards.iter().flat_map(|shard| shard.iter_expanded())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::AtomicSlots;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    #[test]
+    fn sharded() {
+        let ch: ShardedCHash<String> = ShardedCHash::with_shards(6);
+        assert_eq!(ch.shards().len(), 8);
+        for i in 0..1000 {
+            ch.add(format!("{}", i%100));
+        }
+        assert!(ch.contains("42"));
+        assert_eq!(ch.count("42"), 10);
+        assert_eq!(ch.total(), 1000);
+        assert!(ch.remove("42"));
+        assert_eq!(ch.remove_one("43"), Some(9));
+        assert_eq!(ch.total(), 989);
+        // the values are spread between the shards
+        assert!(ch.shards().iter().all(|shard| shard.total() > 0));
+        assert_eq!(ch.iter().iter().count(), 99);
+        assert_eq!(ch.iter().iter_expanded().count(), 989);
+        assert_eq!(ch.snapshot().len(), 99);
+        assert_eq!(ch.top_k(1)[0].1, 10);
+        assert_eq!(ch.size(), ch.shards().iter().map(|shard| shard.size()).sum::<usize>());
+    }
+
+    #[test]
+    fn one_shard() {
+        let ch: ShardedCHash<u32> = ShardedCHash::with_shards(1);
+        for i in 0..100 {
+            ch.add(i);
+        }
+        assert_eq!(ch.shards().len(), 1);
+        assert_eq!(ch.shards()[0].total(), 100);
+        for i in 10..100 {
+            ch.remove(&i);
+        }
+        let before = ch.memory_usage();
+        ch.shrink_to_fit();
+        assert_eq!(ch.size(), 16);
+        assert!(ch.memory_usage() < before);
+        ch.clear();
+        assert_eq!(ch.total(), 0);
+        for i in 0..100 {
+            ch.add(i);
+        }
+        assert_eq!(ch.iteratortable().count(), 100);
+    }
+
+    #[test]
+    fn sharded_threads() {
+        let ch: Arc<ShardedCHash<u64, RandomState, AtomicSlots<u64>>> = Arc::new(ShardedCHash::default());
+        let handles: Vec<_> = (0..4u64)
+            .map(|t| {
+                let ch = ch.clone();
+                spawn(move || {
+                    for i in 0..50_000 {
+                        ch.add(i%20_000 + t);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(ch.total(), 200_000);
+        assert_eq!(ch.count(&10), 4*3);
+        // each shard grew on its own
+        assert!(ch.size() >= 20_003);
+    }
+}
@@ -0,0 +1,205 @@
+// sketch Count-Min concurrent : depth lignes de width compteurs atomiques. sa taille ne dépend pas
+// du nombre de valeurs distinctes, contrairement à CHash qui double, mais les comptes sont approchés
+use crate::sync::AtomicUsize;
+use crate::ConcurrentMultiset;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::Ordering;
+
+/// approximate multiset of fixed size : a value adds its repetitions to one counter of every row
+/// and its count is the smallest of them
+///
+/// a count is never below the exact one, and over it by at most epsilon times the total with
+/// probability 1-delta, see with_error. only the counters are stored, values cannot be listed nor removed
+#[derive(Debug)]
+pub struct CountMinSketch<S = RandomState> {
+    /// the rows one after the other
+    counters: Box<[AtomicUsize]>,
+    width: usize,
+    depth: usize,
+    total: AtomicUsize,
+    hasher: S,
+}
+
+impl CountMinSketch {
+    /// depth rows of width counters, width is rounded up to a power of two
+    pub fn new(width: usize, depth: usize) -> Self {
+        Self::with_hasher(width, depth, RandomState::new())
+    }
+
+    /// epsilon and delta must be in ]0, 1[, see CountMinSketch
+    pub fn with_error(epsilon: f64, delta: f64) -> Self {
+        Self::with_error_and_hasher(epsilon, delta, RandomState::new())
+    }
+}
+
+impl<S: BuildHasher> CountMinSketch<S> {
+    pub fn with_hasher(width: usize, depth: usize, hasher: S) -> Self {
+        assert!(width > 0 && depth > 0, "a CountMinSketch needs at least one counter");
+        let width = width.next_power_of_two();
+        CountMinSketch {
+            counters: (0..width*depth).map(|_| AtomicUsize::new(0)).collect(),
+            width,
+            depth,
+            total: AtomicUsize::new(0),
+            hasher,
+        }
+    }
+
+    /// e/epsilon counters per row and ln(1/delta) rows
+    pub fn with_error_and_hasher(epsilon: f64, delta: f64, hasher: S) -> Self {
+        assert!(epsilon > 0.0 && epsilon < 1.0 && delta > 0.0 && delta < 1.0, "epsilon and delta must be in ]0, 1[");
+        let width = (std::f64::consts::E / epsilon).ceil() as usize;
+        let depth = (1.0 / delta).ln().ceil() as usize;
+        Self::with_hasher(width, depth.max(1), hasher)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// index of the counter of value in every row. the hash is computed once and mixed again for
+    /// each row, two values share a counter in every row only if their whole hashes are equal
+    fn counters_of<Q: Hash + ?Sized>(&self, value: &Q) -> impl Iterator<Item = usize> {
+        let hash = self.hasher.hash_one(value);
+        let width = self.width;
+        (0..self.depth).map(move |row| row*width + (mix(hash, row as u64) as usize & (width-1)))
+    }
+
+    pub fn add<Q: Hash>(&self, value: Q) {
+        self.add_n(value, 1)
+    }
+
+    pub fn add_n<Q: Hash>(&self, value: Q, repetitions: usize) {
+        for i in self.counters_of(&value) {
+            self.counters[i].fetch_add(repetitions, Ordering::Relaxed);
+        }
+        self.total.fetch_add(repetitions, Ordering::SeqCst);
+    }
+
+    /// never below the number of repetitions added, see CountMinSketch for the error
+    pub fn count<Q: Hash + ?Sized>(&self, value: &Q) -> usize {
+        self.counters_of(value)
+            .map(|i| self.counters[i].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// false positives are possible, like with a Bloom filter, false negatives are not
+    pub fn contains<Q: Hash + ?Sized>(&self, value: &Q) -> bool {
+        self.count(value) > 0
+    }
+
+    /// sum of the repetitions added, exact
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::SeqCst)
+    }
+}
+
+/// finalizer of splitmix64 applied to hash moved by row
+fn mix(hash: u64, row: u64) -> u64 {
+    let mut z = hash.wrapping_add(row.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl<T: Hash, S: BuildHasher> ConcurrentMultiset<T> for CountMinSketch<S> {
+    fn add_n(&self, value: T, repetitions: usize) {
+        CountMinSketch::add_n(self, value, repetitions);
+    }
+
+    fn count(&self, value: &T) -> usize {
+        CountMinSketch::count(self, value)
+    }
+
+    fn total(&self) -> usize {
+        CountMinSketch::total(self)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::CHash;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashMap;
+    use std::hash::BuildHasherDefault;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    // the same counters from one run to the next
+    type Deterministic = BuildHasherDefault<DefaultHasher>;
+
+    #[test]
+    fn sizes() {
+        let sketch = CountMinSketch::new(100, 3);
+        assert_eq!((sketch.width(), sketch.depth()), (128, 3));
+        let sketch = CountMinSketch::with_error(0.01, 0.01);
+        assert_eq!((sketch.width(), sketch.depth()), (512, 5));
+    }
+
+    #[test]
+    fn never_under() {
+        let sketch = CountMinSketch::with_error_and_hasher(0.01, 0.01, Deterministic::default());
+        let mut exact = HashMap::new();
+        // value i is added 10_000/(i+1) times
+        for i in 0..10_000u32 {
+            for v in 0..10_000/(i+1) {
+                sketch.add(v);
+                *exact.entry(v).or_insert(0) += 1;
+            }
+        }
+        let total: usize = exact.values().sum();
+        assert_eq!(sketch.total(), total);
+        for (v, &r) in &exact {
+            let count = sketch.count(v);
+            assert!(count >= r);
+            assert!(count - r <= total/100, "count of {} is {} instead of {}", v, count, r);
+        }
+        assert!(sketch.contains(&0));
+        assert_eq!(sketch.count(&10_000), sketch.count(&10_000u32));
+    }
+
+    #[test]
+    fn sketch_threads() {
+        let sketch = Arc::new(CountMinSketch::with_hasher(1 << 12, 4, Deterministic::default()));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let sketch = sketch.clone();
+                spawn(move || {
+                    for i in 0..10_000u64 {
+                        sketch.add_n(i%100, 2);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(sketch.total(), 80_000);
+        assert!((0..100u64).all(|v| sketch.count(&v) >= 800));
+    }
+
+    /// counts the words of text in any multiset
+    fn word_counts<M: ConcurrentMultiset<&'static str>>(multiset: &M, text: &'static str) -> usize {
+        for word in text.split_whitespace() {
+            multiset.add(word);
+        }
+        multiset.count(&"the")
+    }
+
+    #[test]
+    fn exact_or_approximate() {
+        let text = "the cat saw the dog and the bird";
+        let exact: CHash<&str> = CHash::new();
+        let approximate = CountMinSketch::new(64, 4);
+        assert_eq!(word_counts(&exact, text), 3);
+        assert!(word_counts(&approximate, text) >= 3);
+        assert_eq!(approximate.total(), exact.total());
+    }
+}
@@ -0,0 +1,1002 @@
+// stratégies de stockage des cases de Table : un RwLock par case ou des cases atomiques
+use crate::error::Error;
+use std::borrow::Borrow;
+use std::ops::Range;
+use crate::sync::{AtomicU64, AtomicUsize, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(loom)]
+use crate::sync::Poison;
+use std::sync::atomic::Ordering;
+use std::sync::{PoisonError, TryLockError};
+
+/// what Slots::add did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Added {
+    /// the value was not there
+    pub inserted: bool,
+    /// a Container that was empty is now used, always the case of an inserted value except
+    /// in AtomicSlots where a removed value is added back in the slot it left
+    pub claimed: bool,
+    /// number of repetitions added
+    pub repetitions: usize,
+}
+
+impl Added {
+    /// a value added to a Container of its own, or to one it was already in
+    fn new(inserted: bool, repetitions: usize) -> Self {
+        Added { inserted, claimed: inserted, repetitions }
+    }
+}
+
+/// storage of the Containers of a table, probing starts at the home index given by the table
+///
+/// probing visits each Container at most once. CHash always keeps empty Containers so add finds one
+///
+/// a lock poisoned by a panicking thread makes the calls needing it return Error::Poisoned,
+/// a lock held by another thread makes them return Error::WouldBlock when called with Lock::Try
+pub trait Slots<T>: Sized {
+    fn with_len(len: usize) -> Self;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// adds repetitions of value without going over max repetitions, see Added.
+    /// the value is given back with the error
+    fn add(&self, home: usize, value: T, repeatitions: usize, max: usize, lock: Lock) -> Result<Added, (Error, T)>;
+
+    /// number of repetitions of value, 0 if it is not there
+    fn count<Q>(&self, home: usize, value: &Q, lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized;
+
+    /// returns the number of repetitions removed, 0 if the value was not there
+    fn remove<Q>(&self, home: usize, value: &Q, lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized;
+
+    /// returns the number of repetitions left or None if the value was not there
+    fn remove_one<Q>(&self, home: usize, value: &Q, lock: Lock) -> Result<Option<usize>, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized;
+
+    /// calls f on every (value, repetitions) stored
+    fn for_each<F: FnMut(&T, usize)>(&self, lock: Lock, f: F) -> Result<(), Error> {
+        self.for_each_in(0..self.len(), lock, f)
+    }
+
+    /// calls f on every (value, repetitions) stored in the Containers of range
+    fn for_each_in<F: FnMut(&T, usize)>(&self, range: Range<usize>, lock: Lock, mut f: F) -> Result<(), Error> {
+        self.for_each_indexed(range, lock, |_,v,r| f(v,r))
+    }
+
+    /// calls f on every (index, value, repetitions) stored in the Containers of range
+    fn for_each_indexed<F: FnMut(usize, &T, usize)>(&self, range: Range<usize>, lock: Lock, f: F) -> Result<(), Error>;
+
+    /// number of Containers that are not empty, those left unusable by removed values included
+    fn used(&self, lock: Lock) -> Result<usize, Error>;
+
+    /// the values of poisoned Containers are kept
+    fn into_pairs(self) -> Vec<(T, usize)>;
+
+    /// moves the value stored at index out of the table through f, used by incremental resizing
+    ///
+    /// f runs before the value stops being visible so that lookups never miss it in both tables.
+    /// when f gives the value back with an error, it is put back in its Container. always waits
+    /// for the lock : a chunk of the old table left half migrated could not be claimed again
+    fn migrate<F>(&self, index: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(T, usize) -> Result<(), (Error, T)>;
+
+    /// migrates every value of the probe chain starting at home
+    fn migrate_chain<F>(&self, home: usize, lock: Lock, f: F) -> Result<(), Error>
+        where F: FnMut(T, usize) -> Result<(), (Error, T)>;
+
+    /// makes the poisoned locks usable again, see PoisonPolicy::Clear. returns the number of
+    /// Containers cleared
+    fn clear_poisoned(&mut self) -> usize {
+        0
+    }
+
+    /// lock acquisitions that had to wait so far, none for storages that do not count them
+    fn contention(&self) -> Contention {
+        Contention::default()
+    }
+
+    /// bytes used by the Containers, not counting the memory the values own themselves
+    fn memory_usage(&self) -> usize;
+
+    /// false for the values the storage cannot hold, add then returns Error::ReservedValue
+    fn accepts(_value: &T) -> bool {
+        true
+    }
+}
+
+/// what a call does when the lock it needs is held by another thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lock {
+    /// waits for it, like RwLock::read and RwLock::write
+    Wait,
+    /// gives up with Error::WouldBlock, like RwLock::try_read and RwLock::try_write.
+    /// AtomicSlots takes no lock, only a value being moved to a bigger table makes it give up
+    Try,
+}
+
+impl Lock {
+    pub(crate) fn read<V>(self, rw: &RwLock<V>) -> Result<RwLockReadGuard<'_, V>, Error> {
+        match self {
+            Lock::Wait => rw.read().map_err(|_| Error::Poisoned),
+            Lock::Try => rw.try_read().map_err(Error::from),
+        }
+    }
+
+    pub(crate) fn write<V>(self, rw: &RwLock<V>) -> Result<RwLockWriteGuard<'_, V>, Error> {
+        match self {
+            Lock::Wait => rw.write().map_err(|_| Error::Poisoned),
+            Lock::Try => rw.try_write().map_err(Error::from),
+        }
+    }
+}
+
+/// number of lock acquisitions that found the lock taken by another thread, see CHash::stats
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Contention {
+    pub reads: usize,
+    pub writes: usize,
+}
+
+impl std::ops::Add for Contention {
+    type Output = Contention;
+
+    fn add(self, other: Contention) -> Contention {
+        Contention { reads: self.reads + other.reads, writes: self.writes + other.writes }
+    }
+}
+
+/// takes locks with try_read and try_write first, counting the failures before blocking or giving up
+#[derive(Debug, Default)]
+struct Waits {
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+impl Waits {
+    fn read<'a, V>(&self, rw: &'a RwLock<V>, lock: Lock) -> Result<RwLockReadGuard<'a, V>, Error> {
+        match rw.try_read() {
+            Err(TryLockError::WouldBlock) => {
+                self.reads.fetch_add(1, Ordering::Relaxed);
+                lock.read(rw)
+            },
+            result => result.map_err(Error::from),
+        }
+    }
+
+    fn write<'a, V>(&self, rw: &'a RwLock<V>, lock: Lock) -> Result<RwLockWriteGuard<'a, V>, Error> {
+        match rw.try_write() {
+            Err(TryLockError::WouldBlock) => {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+                lock.write(rw)
+            },
+            result => result.map_err(Error::from),
+        }
+    }
+
+    fn contention(&self) -> Contention {
+        Contention { reads: self.reads.load(Ordering::Relaxed), writes: self.writes.load(Ordering::Relaxed) }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Container<T> {
+    Empty,
+    /// a removed value, probing goes on past it so the values further in the chain are still found
+    Tombstone,
+    ElemRepeat(T,usize),
+}
+
+/// one RwLock per Container, works for any value
+#[derive(Debug)]
+pub struct LockedSlots<T> {
+    pub(crate) containers: Vec<RwLock<Container<T>>>,
+    waits: Waits,
+}
+
+impl<T: PartialEq> LockedSlots<T> {
+    /// write lock on the Container holding value, if any
+    fn find_mut<Q>(&self, home: usize, value: &Q, lock: Lock) -> Result<Option<RwLockWriteGuard<'_, Container<T>>>, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        let size = self.containers.len();
+        let mut hash = home;
+        for _ in 0..size {
+            let chosen = self.waits.write(&self.containers[hash], lock)?;
+            match &*chosen {
+                Container::Empty => return Ok(None),
+                Container::ElemRepeat(v,_) if v.borrow()==value => return Ok(Some(chosen)),
+                _ => hash = (hash+1)%size,
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<T: PartialEq> Slots<T> for LockedSlots<T> {
+    fn with_len(len: usize) -> Self {
+        let containers = (0..len)
+                .map(|_| RwLock::new(Container::Empty))
+                .collect();
+        LockedSlots { containers, waits: Waits::default() }
+    }
+
+    fn len(&self) -> usize {
+        self.containers.len()
+    }
+
+    fn add(&self, home: usize, value: T, repeatitions: usize, max: usize, lock: Lock) -> Result<Added, (Error, T)> {
+        let size = self.containers.len();
+        let mut hash = home;
+        for _ in 0..size {
+            let mut chosen = match self.waits.write(&self.containers[hash], lock) {
+                Ok(chosen) => chosen,
+                Err(e) => return Err((e, value)),
+            };
+            match &mut *chosen {
+                Container::ElemRepeat(v,r) => {
+                    if v==&value {
+                        let added = repeatitions.min(max.saturating_sub(*r));
+                        *r += added;
+                        return Ok(Added::new(false, added));
+                    }
+                    else {hash = (hash+1)%size;}
+                },
+                Container::Empty => {
+                    let added = repeatitions.min(max);
+                    *chosen = Container::ElemRepeat(value, added);
+                    return Ok(Added::new(true, added));
+                },
+                // not reused : another thread may be probing past it for the same value
+                Container::Tombstone => {hash = (hash+1)%size;},
+            }
+        }
+        panic!("no empty Container left, CHash keeps some free");
+    }
+
+    fn count<Q>(&self, home: usize, value: &Q, lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        let size = self.containers.len();
+        let mut hash = home;
+        for _ in 0..size {
+            match &*self.waits.read(&self.containers[hash], lock)? {
+                Container::Empty => return Ok(0),
+                Container::ElemRepeat(v,r) if v.borrow()==value => return Ok(*r),
+                _ => hash = (hash+1)%size,
+            }
+        }
+        Ok(0)
+    }
+
+    fn remove<Q>(&self, home: usize, value: &Q, lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        Ok(match self.find_mut(home, value, lock)? {
+            Some(mut chosen) => match std::mem::replace(&mut *chosen, Container::Tombstone) {
+                Container::ElemRepeat(_,r) => r,
+                _ => unreachable!(),
+            },
+            None => 0,
+        })
+    }
+
+    /// the Container becomes a tombstone when it reaches 0
+    fn remove_one<Q>(&self, home: usize, value: &Q, lock: Lock) -> Result<Option<usize>, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        let mut chosen = match self.find_mut(home, value, lock)? {
+            Some(chosen) => chosen,
+            None => return Ok(None),
+        };
+        let left = match &mut *chosen {
+            Container::ElemRepeat(_,r) => {*r -= 1; *r},
+            _ => unreachable!(),
+        };
+        if left == 0 {
+            *chosen = Container::Tombstone;
+        }
+        Ok(Some(left))
+    }
+
+    fn for_each_indexed<F: FnMut(usize, &T, usize)>(&self, range: Range<usize>, lock: Lock, mut f: F) -> Result<(), Error> {
+        for index in range {
+            if let Container::ElemRepeat(v,r) = &*self.waits.read(&self.containers[index], lock)? {
+                f(index, v, *r);
+            }
+        }
+        Ok(())
+    }
+
+    fn used(&self, lock: Lock) -> Result<usize, Error> {
+        let mut used = 0;
+        for container in &self.containers {
+            if !matches!(&*self.waits.read(container, lock)?, Container::Empty) {
+                used += 1;
+            }
+        }
+        Ok(used)
+    }
+
+    fn into_pairs(self) -> Vec<(T, usize)> {
+        self.containers
+            .into_iter()
+            .filter_map(|c| match c.into_inner().unwrap_or_else(PoisonError::into_inner) {
+                Container::ElemRepeat(v,r) => Some((v,r)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// the Container stays write locked while f runs and is left as a tombstone
+    fn migrate<F>(&self, index: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(T, usize) -> Result<(), (Error, T)>
+    {
+        let mut chosen = self.waits.write(&self.containers[index], Lock::Wait)?;
+        if let Container::ElemRepeat(..) = &*chosen {
+            if let Container::ElemRepeat(v,r) = std::mem::replace(&mut *chosen, Container::Tombstone) {
+                if let Err((e, v)) = f(v,r) {
+                    *chosen = Container::ElemRepeat(v,r);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn migrate_chain<F>(&self, home: usize, lock: Lock, mut f: F) -> Result<(), Error>
+        where F: FnMut(T, usize) -> Result<(), (Error, T)>
+    {
+        let size = self.containers.len();
+        let mut hash = home;
+        for _ in 0..size {
+            let mut chosen = self.waits.write(&self.containers[hash], lock)?;
+            match std::mem::replace(&mut *chosen, Container::Tombstone) {
+                Container::Empty => {*chosen = Container::Empty; return Ok(());},
+                Container::ElemRepeat(v,r) => if let Err((e, v)) = f(v,r) {
+                    *chosen = Container::ElemRepeat(v,r);
+                    return Err(e);
+                },
+                Container::Tombstone => (),
+            }
+            hash = (hash+1)%size;
+        }
+        Ok(())
+    }
+
+    /// a poisoned Container becomes a tombstone : its value may be the one whose comparison panicked
+    fn clear_poisoned(&mut self) -> usize {
+        let mut cleared = 0;
+        for lock in &mut self.containers {
+            if lock.is_poisoned() {
+                *lock.get_mut().unwrap_or_else(PoisonError::into_inner) = Container::Tombstone;
+                lock.clear_poison();
+                cleared += 1;
+            }
+        }
+        cleared
+    }
+
+    fn contention(&self) -> Contention {
+        self.waits.contention()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.containers.capacity() * std::mem::size_of::<RwLock<Container<T>>>()
+    }
+}
+
+/// a Container of RobinHoodSlots, distance is how far it is from the home of its value
+#[derive(Debug)]
+enum Bucket<T> {
+    Empty,
+    /// the value was moved to a bigger table, lookups go on past it as if it were still there
+    Moved(usize),
+    Full(T, usize, usize),
+}
+
+impl<T> Bucket<T> {
+    fn distance(&self) -> Option<usize> {
+        match self {
+            Bucket::Empty => None,
+            Bucket::Moved(d) | Bucket::Full(_,_,d) => Some(*d),
+        }
+    }
+}
+
+/// Robin Hood probing : a value being inserted takes the Container of any value closer to its home,
+/// which then goes on probing. probe lengths stay short at high load, a lookup stops as soon as it
+/// meets a value closer to its home than the one looked for, and a removal shifts the rest of the
+/// chain back instead of leaving a tombstone
+///
+/// a displacement can move every value of a chain, so the Containers share one RwLock :
+/// lookups run in parallel but adds and removals are serialized
+#[derive(Debug)]
+pub struct RobinHoodSlots<T> {
+    buckets: RwLock<Vec<Bucket<T>>>,
+    len: usize,
+    waits: Waits,
+}
+
+impl<T: PartialEq> RobinHoodSlots<T> {
+    /// index of the Container holding value, if any
+    fn find<Q>(buckets: &[Bucket<T>], home: usize, value: &Q) -> Option<usize>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        let size = buckets.len();
+        let mut hash = home;
+        for distance in 0..size {
+            match &buckets[hash] {
+                Bucket::Full(v,_,_) if v.borrow()==value => return Some(hash),
+                b => if b.distance().is_none_or(|d| d < distance) {
+                    return None;
+                },
+            }
+            hash = (hash+1)%size;
+        }
+        None
+    }
+
+    /// empties the Container at index and shifts back the values after it that are not at home
+    fn shift_back(buckets: &mut [Bucket<T>], mut index: usize) {
+        let size = buckets.len();
+        loop {
+            let next = (index+1)%size;
+            match std::mem::replace(&mut buckets[next], Bucket::Empty) {
+                Bucket::Full(v,r,d) if d > 0 => buckets[index] = Bucket::Full(v,r,d-1),
+                Bucket::Moved(d) if d > 0 => buckets[index] = Bucket::Moved(d-1),
+                stays => {
+                    buckets[next] = stays;
+                    buckets[index] = Bucket::Empty;
+                    return;
+                },
+            }
+            index = next;
+        }
+    }
+}
+
+impl<T: PartialEq> Slots<T> for RobinHoodSlots<T> {
+    fn with_len(len: usize) -> Self {
+        RobinHoodSlots { buckets: RwLock::new((0..len).map(|_| Bucket::Empty).collect()), len, waits: Waits::default() }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn add(&self, home: usize, value: T, repeatitions: usize, max: usize, lock: Lock) -> Result<Added, (Error, T)> {
+        let mut buckets = match self.waits.write(&self.buckets, lock) {
+            Ok(buckets) => buckets,
+            Err(e) => return Err((e, value)),
+        };
+        if let Some(index) = Self::find(&buckets, home, &value) {
+            if let Bucket::Full(_,r,_) = &mut buckets[index] {
+                let added = repeatitions.min(max.saturating_sub(*r));
+                *r += added;
+                return Ok(Added::new(false, added));
+            }
+        }
+        let size = buckets.len();
+        let added = repeatitions.min(max);
+        let mut carried = (value, added, 0);
+        let mut hash = home;
+        for _ in 0..size {
+            match &mut buckets[hash] {
+                Bucket::Full(v,r,d) => if *d < carried.2 {
+                    std::mem::swap(v, &mut carried.0);
+                    std::mem::swap(r, &mut carried.1);
+                    std::mem::swap(d, &mut carried.2);
+                },
+                // only found in a table being migrated, which is never added to
+                chosen => {
+                    *chosen = Bucket::Full(carried.0, carried.1, carried.2);
+                    return Ok(Added::new(true, added));
+                },
+            }
+            hash = (hash+1)%size;
+            carried.2 += 1;
+        }
+        panic!("no empty Container left, CHash keeps some free");
+    }
+
+    fn count<Q>(&self, home: usize, value: &Q, lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        let buckets = self.waits.read(&self.buckets, lock)?;
+        Ok(match Self::find(&buckets, home, value).map(|index| &buckets[index]) {
+            Some(Bucket::Full(_,r,_)) => *r,
+            _ => 0,
+        })
+    }
+
+    fn remove<Q>(&self, home: usize, value: &Q, lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        let mut buckets = self.waits.write(&self.buckets, lock)?;
+        let index = match Self::find(&buckets, home, value) {
+            Some(index) => index,
+            None => return Ok(0),
+        };
+        let removed = match &buckets[index] {
+            Bucket::Full(_,r,_) => *r,
+            _ => unreachable!(),
+        };
+        Self::shift_back(&mut buckets, index);
+        Ok(removed)
+    }
+
+    /// the chain is shifted back when the value reaches 0
+    fn remove_one<Q>(&self, home: usize, value: &Q, lock: Lock) -> Result<Option<usize>, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        let mut buckets = self.waits.write(&self.buckets, lock)?;
+        let index = match Self::find(&buckets, home, value) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let left = match &mut buckets[index] {
+            Bucket::Full(_,r,_) => {*r -= 1; *r},
+            _ => unreachable!(),
+        };
+        if left == 0 {
+            Self::shift_back(&mut buckets, index);
+        }
+        Ok(Some(left))
+    }
+
+    fn for_each_indexed<F: FnMut(usize, &T, usize)>(&self, range: Range<usize>, lock: Lock, mut f: F) -> Result<(), Error> {
+        let buckets = self.waits.read(&self.buckets, lock)?;
+        for index in range {
+            if let Bucket::Full(v,r,_) = &buckets[index] {
+                f(index, v, *r);
+            }
+        }
+        Ok(())
+    }
+
+    fn used(&self, lock: Lock) -> Result<usize, Error> {
+        Ok(self.waits.read(&self.buckets, lock)?.iter().filter(|b| b.distance().is_some()).count())
+    }
+
+    fn into_pairs(self) -> Vec<(T, usize)> {
+        self.buckets
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+            .into_iter()
+            .filter_map(|b| match b {
+                Bucket::Full(v,r,_) => Some((v,r)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// no shift while migrating : the value is replaced by a Moved Container keeping its distance,
+    /// so that the indexes still to migrate do not change and lookups still stop at the right place
+    fn migrate<F>(&self, index: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(T, usize) -> Result<(), (Error, T)>
+    {
+        let mut buckets = self.waits.write(&self.buckets, Lock::Wait)?;
+        if let Bucket::Full(_,_,d) = buckets[index] {
+            if let Bucket::Full(v,r,_) = std::mem::replace(&mut buckets[index], Bucket::Moved(d)) {
+                if let Err((e, v)) = f(v,r) {
+                    buckets[index] = Bucket::Full(v,r,d);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// stops where a lookup from home would, every value with this home is before
+    fn migrate_chain<F>(&self, home: usize, lock: Lock, mut f: F) -> Result<(), Error>
+        where F: FnMut(T, usize) -> Result<(), (Error, T)>
+    {
+        let mut buckets = self.waits.write(&self.buckets, lock)?;
+        let size = buckets.len();
+        let mut hash = home;
+        for distance in 0..size {
+            match buckets[hash].distance() {
+                Some(d) if d >= distance => (),
+                _ => return Ok(()),
+            }
+            if let Bucket::Full(_,_,d) = buckets[hash] {
+                if let Bucket::Full(v,r,_) = std::mem::replace(&mut buckets[hash], Bucket::Moved(d)) {
+                    if let Err((e, v)) = f(v,r) {
+                        buckets[hash] = Bucket::Full(v,r,d);
+                        return Err(e);
+                    }
+                }
+            }
+            hash = (hash+1)%size;
+        }
+        Ok(())
+    }
+
+    /// the values are kept : a panic cannot leave a displacement or a shift half done,
+    /// only the comparisons of the values run user code while the lock is held
+    fn clear_poisoned(&mut self) -> usize {
+        if !self.buckets.is_poisoned() {
+            return 0;
+        }
+        self.buckets.clear_poison();
+        1
+    }
+
+    fn contention(&self) -> Contention {
+        self.waits.contention()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.len * std::mem::size_of::<Bucket<T>>()
+    }
+}
+
+/// integer-like values that fit in a u64, see AtomicSlots
+///
+/// u64::MAX, usize::MAX on 64 bits targets, i64::MAX and isize::MAX are reserved and cannot be added,
+/// see Error::ReservedValue
+pub trait AtomicKey: Copy {
+    fn to_bits(self) -> u64;
+    fn from_bits(bits: u64) -> Self;
+}
+
+macro_rules! atomic_key_unsigned {
+    ($($t:ty),*) => {$(
+        impl AtomicKey for $t {
+            fn to_bits(self) -> u64 { self as u64 }
+            fn from_bits(bits: u64) -> Self { bits as $t }
+        }
+    )*};
+}
+
+// the sign bit is flipped so that -1 does not end on the reserved u64::MAX
+macro_rules! atomic_key_signed {
+    ($($t:ty),*) => {$(
+        impl AtomicKey for $t {
+            fn to_bits(self) -> u64 { (self as i64 as u64) ^ (1 << 63) }
+            fn from_bits(bits: u64) -> Self { (bits ^ (1 << 63)) as i64 as $t }
+        }
+    )*};
+}
+
+atomic_key_unsigned!(u8, u16, u32, u64, usize);
+atomic_key_signed!(i8, i16, i32, i64, isize);
+
+const EMPTY: u64 = 0;
+/// counter of a slot whose value is being moved to another table
+const MOVING: usize = usize::MAX;
+
+/// keys are stored shifted by one so that 0 marks an empty slot
+fn encode<T: AtomicKey>(value: T) -> u64 {
+    value.to_bits().wrapping_add(1)
+}
+
+fn decode<T: AtomicKey>(key: u64) -> T {
+    T::from_bits(key.wrapping_sub(1))
+}
+
+/// lock free slots for integer-like values : an atomic key claimed by compare and swap and an atomic counter
+///
+/// a key never leaves its slot, removing it only brings its counter back to 0 so probing stays correct
+#[derive(Debug)]
+pub struct AtomicSlots<T> {
+    keys: Vec<AtomicU64>,
+    counts: Vec<AtomicUsize>,
+    /// compare and swap claiming an empty slot lost to another thread
+    lost_claims: AtomicUsize,
+    _values: std::marker::PhantomData<T>,
+}
+
+impl<T: AtomicKey> AtomicSlots<T> {
+    /// index of the slot holding value, if any
+    fn find<Q>(&self, home: usize, value: &Q) -> Option<usize>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        let size = self.keys.len();
+        let mut hash = home;
+        for _ in 0..size {
+            match self.keys[hash].load(Ordering::SeqCst) {
+                EMPTY => return None,
+                k if decode::<T>(k).borrow() == value => return Some(hash),
+                _ => hash = (hash+1)%size,
+            }
+        }
+        None
+    }
+}
+
+impl<T: AtomicKey> Slots<T> for AtomicSlots<T> {
+    fn with_len(len: usize) -> Self {
+        AtomicSlots {
+            keys: (0..len).map(|_| AtomicU64::new(EMPTY)).collect(),
+            counts: (0..len).map(|_| AtomicUsize::new(0)).collect(),
+            lost_claims: AtomicUsize::new(0),
+            _values: std::marker::PhantomData,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn add(&self, home: usize, value: T, repeatitions: usize, max: usize, _lock: Lock) -> Result<Added, (Error, T)> {
+        let max = max.min(MOVING-1);
+        let key = encode(value);
+        if key == EMPTY {
+            return Err((Error::ReservedValue, value));
+        }
+        let size = self.keys.len();
+        let mut hash = home;
+        for _ in 0..size {
+            let mut current = self.keys[hash].load(Ordering::SeqCst);
+            let mut claimed = false;
+            if current == EMPTY {
+                match self.keys[hash].compare_exchange(EMPTY, key, Ordering::SeqCst, Ordering::SeqCst) {
+                    Ok(_) => claimed = true,
+                    Err(other) => {
+                        self.lost_claims.fetch_add(1, Ordering::Relaxed);
+                        current = other;
+                    },
+                }
+            }
+            if claimed || current == key {
+                let previous = match self.counts[hash].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |c| {
+                    Some(c + repeatitions.min(max.saturating_sub(c)))
+                }) {
+                    Ok(c) | Err(c) => c,
+                };
+                // the add that brings the counter up from 0 inserts the value : the one that claimed the
+                // slot unless another thread added the same value first, or the first one after the
+                // value was removed down to 0, which reuses the slot without claiming it
+                return Ok(Added {
+                    inserted: previous == 0,
+                    claimed,
+                    repetitions: repeatitions.min(max.saturating_sub(previous)),
+                });
+            }
+            hash = (hash+1)%size;
+        }
+        panic!("no empty slot left, CHash keeps some free");
+    }
+
+    fn count<Q>(&self, home: usize, value: &Q, _lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        Ok(self.find(home, value)
+            .map_or(0, |i| self.counts[i].load(Ordering::SeqCst)))
+    }
+
+    fn remove<Q>(&self, home: usize, value: &Q, _lock: Lock) -> Result<usize, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        Ok(self.find(home, value)
+            .map_or(0, |i| self.counts[i].swap(0, Ordering::SeqCst)))
+    }
+
+    fn remove_one<Q>(&self, home: usize, value: &Q, _lock: Lock) -> Result<Option<usize>, Error>
+        where T: Borrow<Q>, Q: PartialEq + ?Sized
+    {
+        Ok(self.find(home, value).and_then(|i| {
+            self.counts[i]
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |c| c.checked_sub(1))
+                .ok()
+                .map(|previous| previous-1)
+        }))
+    }
+
+    fn for_each_indexed<F: FnMut(usize, &T, usize)>(&self, range: Range<usize>, _lock: Lock, mut f: F) -> Result<(), Error> {
+        for index in range {
+            let key = self.keys[index].load(Ordering::SeqCst);
+            let count = self.counts[index].load(Ordering::SeqCst);
+            if key != EMPTY && count > 0 && count != MOVING {
+                f(index, &decode(key), count);
+            }
+        }
+        Ok(())
+    }
+
+    /// a removed value keeps its slot, it counts as used
+    fn used(&self, _lock: Lock) -> Result<usize, Error> {
+        Ok(self.keys.iter().filter(|k| k.load(Ordering::SeqCst) != EMPTY).count())
+    }
+
+    fn into_pairs(self) -> Vec<(T, usize)> {
+        self.keys
+            .into_iter()
+            .zip(self.counts)
+            .map(|(k,c)| (k.into_inner(), c.into_inner()))
+            .filter(|&(k,c)| k != EMPTY && c > 0 && c != MOVING)
+            .map(|(k,c)| (decode(k), c))
+            .collect()
+    }
+
+    /// the counter is set to MOVING while f runs, concurrent migrations of the same slot wait for it
+    fn migrate<F>(&self, index: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(T, usize) -> Result<(), (Error, T)>
+    {
+        self.migrate_slot(index, Lock::Wait, f)
+    }
+
+    fn migrate_chain<F>(&self, home: usize, lock: Lock, mut f: F) -> Result<(), Error>
+        where F: FnMut(T, usize) -> Result<(), (Error, T)>
+    {
+        let size = self.keys.len();
+        let mut hash = home;
+        for _ in 0..size {
+            if self.keys[hash].load(Ordering::SeqCst) == EMPTY {
+                return Ok(());
+            }
+            self.migrate_slot(hash, lock, &mut f)?;
+            hash = (hash+1)%size;
+        }
+        Ok(())
+    }
+
+    /// lost claims are counted as waiting writes, the counters being lock free
+    fn contention(&self) -> Contention {
+        Contention { reads: 0, writes: self.lost_claims.load(Ordering::Relaxed) }
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.keys.capacity() * std::mem::size_of::<AtomicU64>() + self.counts.capacity() * std::mem::size_of::<AtomicUsize>()
+    }
+    fn accepts(value: &T) -> bool {
+        encode(*value) != EMPTY
+    }
+}
+
+impl<T: AtomicKey> AtomicSlots<T> {
+    /// see Slots::migrate, with Lock::Try a value being moved by another thread is not waited for
+    fn migrate_slot<F>(&self, index: usize, lock: Lock, f: F) -> Result<(), Error>
+        where F: FnOnce(T, usize) -> Result<(), (Error, T)>
+    {
+        let key = self.keys[index].load(Ordering::SeqCst);
+        if key == EMPTY {
+            return Ok(());
+        }
+        loop {
+            match self.counts[index].load(Ordering::SeqCst) {
+                0 => return Ok(()),
+                MOVING if lock == Lock::Try => return Err(Error::WouldBlock),
+                MOVING => crate::sync::yield_now(),
+                count => {
+                    if self.counts[index]
+                        .compare_exchange(count, MOVING, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_ok()
+                    {
+                        let moved = f(decode(key), count);
+                        self.counts[index].store(if moved.is_ok() { 0 } else { count }, Ordering::SeqCst);
+                        return moved.map_err(|(e, _)| e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atomic_keys_roundtrip() {
+        for &v in &[0i64, -1, 1, i64::MIN, i64::MAX-1] {
+            assert_eq!(i64::from_bits(v.to_bits()), v);
+            assert_eq!(decode::<i64>(encode(v)), v);
+        }
+        for &v in &[0u8, 1, u8::MAX] {
+            assert_eq!(decode::<u8>(encode(v)), v);
+        }
+        assert_eq!(encode(u64::MAX), EMPTY);
+    }
+
+    #[test]
+    fn atomic_slots() {
+        let slots = AtomicSlots::with_len(8);
+        assert_eq!(slots.add(3, -1i32, 1, usize::MAX, Lock::Wait), Ok(Added::new(true, 1)));
+        assert_eq!(slots.add(3, -1, 2, usize::MAX, Lock::Wait), Ok(Added::new(false, 2)));
+        assert_eq!(slots.add(3, 5, 1, usize::MAX, Lock::Wait), Ok(Added::new(true, 1)));
+        assert_eq!(slots.count(3, &-1, Lock::Wait), Ok(3));
+        assert_eq!(slots.count(3, &5, Lock::Wait), Ok(1));
+        assert_eq!(slots.remove_one(3, &5, Lock::Wait), Ok(Some(0)));
+        assert_eq!(slots.remove_one(3, &5, Lock::Wait), Ok(None));
+        // the key stays in its slot and is reused by the next add, which finds the value absent
+        // but claims no empty slot
+        assert_eq!(slots.add(3, 5, 4, 2, Lock::Wait), Ok(Added { inserted: true, claimed: false, repetitions: 2 }));
+        assert_eq!(slots.add(3, 5, 1, 2, Lock::Wait), Ok(Added::new(false, 0)));
+        assert_eq!(slots.remove(3, &-1, Lock::Wait), Ok(3));
+        let mut pairs = slots.into_pairs();
+        pairs.sort();
+        assert_eq!(vec![(5,2)], pairs);
+        let slots = AtomicSlots::with_len(8);
+        assert!(!AtomicSlots::accepts(&u64::MAX));
+        assert_eq!(slots.add(3, u64::MAX, 1, usize::MAX, Lock::Wait), Err((Error::ReservedValue, u64::MAX)));
+        assert_eq!(slots.used(Lock::Wait), Ok(0));
+    }
+
+    #[test]
+    fn robin_hood_slots() {
+        let slots = RobinHoodSlots::with_len(8);
+        // 'a' and 'b' share home 6, the chain wraps around
+        assert_eq!(slots.add(6, 'a', 1, usize::MAX, Lock::Wait), Ok(Added::new(true, 1)));
+        assert_eq!(slots.add(6, 'b', 2, usize::MAX, Lock::Wait), Ok(Added::new(true, 2)));
+        assert_eq!(slots.add(7, 'c', 1, usize::MAX, Lock::Wait), Ok(Added::new(true, 1)));
+        // 'b' is already farther from its home than 'c' would be at 7, so 'c' goes on to 0
+        let mut positions = Vec::new();
+        slots.for_each_indexed(0..8, Lock::Wait, |i,&v,_| positions.push((i,v))).unwrap();
+        assert_eq!(positions, vec![(0,'c'), (6,'a'), (7,'b')]);
+        assert_eq!(slots.add(6, 'b', 3, 4, Lock::Wait), Ok(Added::new(false, 2)));
+        assert_eq!(slots.count(7, &'c', Lock::Wait), Ok(1));
+        // the lookup stops at the empty Container 1
+        assert_eq!(slots.count(1, &'z', Lock::Wait), Ok(0));
+        // the chain shifts back over the removed value
+        assert_eq!(slots.remove(6, &'a', Lock::Wait), Ok(1));
+        positions.clear();
+        slots.for_each_indexed(0..8, Lock::Wait, |i,&v,_| positions.push((i,v))).unwrap();
+        assert_eq!(positions, vec![(6,'b'), (7,'c')]);
+        assert_eq!(slots.remove_one(7, &'c', Lock::Wait), Ok(Some(0)));
+        assert_eq!(slots.count(7, &'c', Lock::Wait), Ok(0));
+        assert_eq!(slots.count(6, &'b', Lock::Wait), Ok(4));
+        assert_eq!(slots.into_pairs(), vec![('b',4)]);
+    }
+
+    #[test]
+    fn robin_hood_migrate() {
+        let slots = RobinHoodSlots::with_len(8);
+        for v in 0..3 {
+            slots.add(2, v, 1, usize::MAX, Lock::Wait).unwrap();
+        }
+        slots.add(3, 10, 1, usize::MAX, Lock::Wait).unwrap();
+        let mut moved = Vec::new();
+        slots.migrate(3, |v,_| {moved.push(v); Ok(())}).unwrap();
+        // the Moved Container keeps the lookups of the rest of the chain going
+        assert_eq!(slots.count(2, &2, Lock::Wait), Ok(1));
+        assert_eq!(slots.count(3, &10, Lock::Wait), Ok(1));
+        // 10 is closer to its home than a value of home 2 could be there, the chain stops before it
+        slots.migrate_chain(2, Lock::Wait, |v,_| {moved.push(v); Ok(())}).unwrap();
+        assert_eq!(moved, vec![1, 0, 2]);
+        assert_eq!(slots.into_pairs(), vec![(10,1)]);
+    }
+
+    #[test]
+    fn failed_migrations() {
+        // a value that cannot be moved stays where it was
+        let locked = LockedSlots::with_len(4);
+        locked.add(1, 'a', 2, usize::MAX, Lock::Wait).unwrap();
+        assert_eq!(locked.migrate(1, |v,_| Err((Error::Poisoned, v))), Err(Error::Poisoned));
+        assert_eq!(locked.count(1, &'a', Lock::Wait), Ok(2));
+        let robin_hood = RobinHoodSlots::with_len(4);
+        robin_hood.add(1, 'a', 2, usize::MAX, Lock::Wait).unwrap();
+        assert_eq!(robin_hood.migrate_chain(1, Lock::Wait, |v,_| Err((Error::Poisoned, v))), Err(Error::Poisoned));
+        assert_eq!(robin_hood.count(1, &'a', Lock::Wait), Ok(2));
+        let atomic = AtomicSlots::with_len(4);
+        atomic.add(1, 7u8, 2, usize::MAX, Lock::Wait).unwrap();
+        assert_eq!(atomic.migrate(1, |v,_| Err((Error::Poisoned, v))), Err(Error::Poisoned));
+        assert_eq!(atomic.count(1, &7, Lock::Wait), Ok(2));
+    }
+
+    #[test]
+    fn clear_poisoned() {
+        let mut slots = LockedSlots::with_len(4);
+        slots.add(0, 1u32, 1, usize::MAX, Lock::Wait).unwrap();
+        slots.add(2, 2, 1, usize::MAX, Lock::Wait).unwrap();
+        let poisoned = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = slots.containers[2].write().unwrap();
+            panic!("poisoning");
+        }));
+        assert!(poisoned.is_err());
+        assert_eq!(slots.count(2, &2, Lock::Wait), Err(Error::Poisoned));
+        assert_eq!(slots.add(2, 3, 1, usize::MAX, Lock::Wait), Err((Error::Poisoned, 3)));
+        // the lookup of 1 does not go through Container 2
+        assert_eq!(slots.count(0, &1, Lock::Wait), Ok(1));
+        assert_eq!(slots.clear_poisoned(), 1);
+        assert_eq!(slots.count(2, &2, Lock::Wait), Ok(0));
+        assert_eq!(slots.add(2, 3, 1, usize::MAX, Lock::Wait), Ok(Added::new(true, 1)));
+        assert_eq!(slots.clear_poisoned(), 0);
+    }
+}
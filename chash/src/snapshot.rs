@@ -0,0 +1,315 @@
+// format binaire pour sauvegarder un CHash entre deux exécutions
+//
+// magic "CHSH", version (1 octet), taille de la table, max_load_factor (f64 little endian),
+// max_repetitions, nombre de valeurs, puis chaque valeur suivie de son nombre de répétitions.
+// les entiers non signés sont en LEB128, les signés en zigzag puis LEB128
+use crate::{max_used, CHash, Lock, Slots};
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hash};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"CHSH";
+const VERSION: u8 = 1;
+
+/// most Containers allocated before the values of a snapshot or of a serialized CHash are read,
+/// the table of a bigger one grows as they are added
+pub(crate) const MAX_PRESIZE: usize = 1 << 20;
+
+/// values that can be written in a snapshot
+pub trait SnapshotValue: Sized {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()>;
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self>;
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) fn write_varint<W: Write>(w: &mut W, mut n: u64) -> io::Result<()> {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            return w.write_all(&[byte]);
+        }
+        w.write_all(&[byte | 0x80])?;
+    }
+}
+
+pub(crate) fn read_varint<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        r.read_exact(&mut byte)?;
+        n |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+macro_rules! snapshot_unsigned {
+    ($($t:ty),*) => {$(
+        impl SnapshotValue for $t {
+            fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                write_varint(w, *self as u64)
+            }
+            fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+                let n = read_varint(r)?;
+                <$t>::try_from(n).map_err(|_| invalid("integer out of range"))
+            }
+        }
+    )*};
+}
+
+macro_rules! snapshot_signed {
+    ($($t:ty),*) => {$(
+        impl SnapshotValue for $t {
+            fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                let n = *self as i64;
+                write_varint(w, ((n << 1) ^ (n >> 63)) as u64)
+            }
+            fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+                let n = read_varint(r)?;
+                let n = ((n >> 1) as i64) ^ -((n & 1) as i64);
+                <$t>::try_from(n).map_err(|_| invalid("integer out of range"))
+            }
+        }
+    )*};
+}
+
+snapshot_unsigned!(u8, u16, u32, u64, usize);
+snapshot_signed!(i8, i16, i32, i64, isize);
+
+impl SnapshotValue for bool {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&[*self as u8])
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        match u8::read_from(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("invalid bool")),
+        }
+    }
+}
+
+impl SnapshotValue for char {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u32).write_to(w)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        char::from_u32(u32::read_from(r)?).ok_or_else(|| invalid("invalid char"))
+    }
+}
+
+impl SnapshotValue for Vec<u8> {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_varint(w, self.len() as u64)?;
+        w.write_all(self)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let len = read_varint(r)?;
+        let mut bytes = Vec::new();
+        r.take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+}
+
+impl SnapshotValue for String {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_varint(w, self.len() as u64)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        String::from_utf8(Vec::read_from(r)?).map_err(|_| invalid("invalid utf-8"))
+    }
+}
+
+impl<T, S: BuildHasher + Clone, B: Slots<T>> CHash<T, S, B> {
+    /// writes every (value, repetitions) pair along with the table size and settings.
+    /// the pairs are encoded while each Container is read, then written at once
+    pub fn write_snapshot<W: Write>(&self, mut w: W) -> io::Result<()>
+        where T: SnapshotValue + Hash
+    {
+        let tables = self.recovering(Lock::Wait, || self.settled(Lock::Wait)).map_err(io::Error::other)?;
+        let mut pairs = Vec::new();
+        let mut len = 0u64;
+        let mut result = Ok(());
+        tables.current.slots.for_each(Lock::Wait, |v,r| {
+            if result.is_ok() {
+                result = v.write_to(&mut pairs).and_then(|_| write_varint(&mut pairs, r as u64));
+                len += 1;
+            }
+        }).map_err(io::Error::other)?;
+        result?;
+        let size = tables.current.size;
+        drop(tables);
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+        write_varint(&mut w, size as u64)?;
+        w.write_all(&self.max_load_factor.to_le_bytes())?;
+        write_varint(&mut w, self.max_repetitions as u64)?;
+        write_varint(&mut w, len)?;
+        w.write_all(&pairs)?;
+        w.flush()
+    }
+
+    /// rebuilds a CHash written by write_snapshot, its table is allocated once at the saved size.
+    /// tables bigger than MAX_PRESIZE grow as the values are read : a corrupt header makes
+    /// it return an error instead of allocating more than the values need
+    pub fn read_snapshot<R: Read>(mut r: R) -> io::Result<Self>
+        where T: SnapshotValue + Hash, S: Default
+    {
+        let mut header = [0u8; 5];
+        r.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid("not a CHash snapshot"));
+        }
+        if header[4] != VERSION {
+            return Err(invalid("unsupported CHash snapshot version"));
+        }
+        let size = read_varint(&mut r)?;
+        let mut max_load_factor = [0u8; 8];
+        r.read_exact(&mut max_load_factor)?;
+        let max_load_factor = f64::from_le_bytes(max_load_factor);
+        if !(max_load_factor > 0.0 && max_load_factor <= 1.0) {
+            return Err(invalid("invalid max_load_factor"));
+        }
+        let max_repetitions = usize::try_from(read_varint(&mut r)?).unwrap_or(usize::MAX);
+        if max_repetitions == 0 {
+            return Err(invalid("invalid max_repetitions"));
+        }
+        let len = usize::try_from(read_varint(&mut r)?).map_err(|_| invalid("too many values"))?;
+        if !size.is_power_of_two() || size < 4 || size > (usize::MAX / 2) as u64 {
+            return Err(invalid("invalid table size"));
+        }
+        let size = size as usize;
+        if len > max_used(size, max_load_factor) {
+            return Err(invalid("more values than the table can hold"));
+        }
+        let mut ch = CHash::with_size(size.min(MAX_PRESIZE), max_load_factor, S::default());
+        ch.set_max_repetitions(max_repetitions);
+        for _ in 0..len {
+            let value = T::read_from(&mut r)?;
+            let repetitions = usize::try_from(read_varint(&mut r)?).map_err(|_| invalid("too many repetitions"))?;
+            ch.add_n(value, repetitions);
+        }
+        Ok(ch)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::{AtomicCHash, AtomicSlots};
+    use std::collections::hash_map::RandomState;
+
+    #[test]
+    fn varints() {
+        let mut bytes = Vec::new();
+        for &n in &[0, 1, 127, 128, 300, u64::MAX] {
+            write_varint(&mut bytes, n).unwrap();
+        }
+        assert_eq!(bytes.len(), 1+1+1+2+2+10);
+        let mut r = &bytes[..];
+        for &n in &[0, 1, 127, 128, 300, u64::MAX] {
+            assert_eq!(read_varint(&mut r).unwrap(), n);
+        }
+        for &n in &[0i64, -1, 1, i64::MIN, i64::MAX] {
+            let mut bytes = Vec::new();
+            n.write_to(&mut bytes).unwrap();
+            assert_eq!(i64::read_from(&mut &bytes[..]).unwrap(), n);
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let ch: CHash<String> = CHash::with_max_repetitions(1000);
+        for i in 0..3000 {
+            ch.add(format!("token{}", i%700));
+        }
+        let mut bytes = Vec::new();
+        ch.write_snapshot(&mut bytes).unwrap();
+        let restored: CHash<String> = CHash::read_snapshot(&bytes[..]).unwrap();
+        assert_eq!(restored.size(), ch.size());
+        assert_eq!(restored.max_repetitions(), 1000);
+        assert_eq!(restored.total(), 3000);
+        // allocated at the right size at once
+        assert_eq!(restored.table.read().unwrap().resizes, 0);
+        let mut expected = ch.snapshot();
+        let mut got = restored.snapshot();
+        expected.sort();
+        got.sort();
+        assert_eq!(expected, got);
+    }
+
+    #[test]
+    fn roundtrip_atomic() {
+        let ch = AtomicCHash::<i64>::default();
+        for i in -100..100 {
+            ch.add_n(i, (i.unsigned_abs() as usize)+1);
+        }
+        let mut bytes = Vec::new();
+        ch.write_snapshot(&mut bytes).unwrap();
+        let restored: CHash<i64, RandomState, AtomicSlots<i64>> = CHash::read_snapshot(&bytes[..]).unwrap();
+        assert_eq!(restored.count(&-100), 101);
+        assert_eq!(restored.total(), ch.total());
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let ch: CHash<u32> = CHash::new();
+        ch.add(7);
+        let mut bytes = Vec::new();
+        ch.write_snapshot(&mut bytes).unwrap();
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        let wrong_version = {
+            let mut b = bytes.clone();
+            b[4] = VERSION+1;
+            b
+        };
+        let truncated = &bytes[..bytes.len()-1];
+        for broken in [&wrong_magic[..], &wrong_version[..], truncated] {
+            assert!(CHash::<u32>::read_snapshot(broken).is_err());
+        }
+    }
+
+    /// a header with the given table size and number of values, without the values
+    fn header(size: u64, len: u64) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        write_varint(&mut bytes, size).unwrap();
+        bytes.extend_from_slice(&0.75f64.to_le_bytes());
+        write_varint(&mut bytes, u64::MAX).unwrap();
+        write_varint(&mut bytes, len).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn corrupt_headers() {
+        let kind = |bytes: &[u8]| CHash::<u32>::read_snapshot(bytes).err().map(|e| e.kind());
+        assert_eq!(kind(&header(4, 1 << 62)), Some(io::ErrorKind::InvalidData));
+        assert_eq!(kind(&header(1 << 10, 1 << 10)), Some(io::ErrorKind::InvalidData));
+        assert_eq!(kind(&header(6, 1)), Some(io::ErrorKind::InvalidData));
+        assert_eq!(kind(&header(1 << 63, 1)), Some(io::ErrorKind::InvalidData));
+        // a huge table announced with values missing is not allocated
+        assert_eq!(kind(&header(1 << 62, 1 << 61)), Some(io::ErrorKind::UnexpectedEof));
+        let mut one = header(1 << 40, 1);
+        write_varint(&mut one, 7).unwrap();
+        write_varint(&mut one, 2).unwrap();
+        let restored = CHash::<u32>::read_snapshot(&one[..]).unwrap();
+        assert_eq!(restored.count(&7), 2);
+        assert_eq!(restored.size(), MAX_PRESIZE);
+    }
+}
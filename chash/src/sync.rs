@@ -0,0 +1,28 @@
+// primitives de synchronisation de CHash : celles de loom quand la crate est compilée avec
+// RUSTFLAGS="--cfg loom", pour que loom explore tous les entrelacements, sinon celles de std
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+#[cfg(loom)]
+pub(crate) use loom::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(loom)]
+pub(crate) use loom::thread::yield_now;
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+#[cfg(not(loom))]
+pub(crate) use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(not(loom))]
+pub(crate) use std::thread::yield_now;
+
+/// the poisoning methods of std::sync::RwLock, a loom RwLock is never poisoned
+#[cfg(loom)]
+pub(crate) trait Poison {
+    fn is_poisoned(&self) -> bool {
+        false
+    }
+
+    fn clear_poison(&self) {}
+}
+
+#[cfg(loom)]
+impl<T> Poison for RwLock<T> {}
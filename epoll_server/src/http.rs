@@ -0,0 +1,457 @@
+// analyse incrémentale des requêtes HTTP/1.1 : les octets de chaque read sont ajoutés au tampon
+// de la connexion et l'analyse reprend là où elle s'était arrêtée, sans rien supposer de l'encodage
+use std::fmt;
+use std::mem;
+use std::ops::Range;
+
+/// longest request line, header line or chunk size line accepted
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+/// largest body accepted, chunked or not
+const MAX_BODY: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    /// 0 for HTTP/1.0, 1 for HTTP/1.1
+    pub minor_version: u8,
+    /// names in lower case, values without the whitespace around them. they are bytes,
+    /// HTTP does not say how they are encoded
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// the chunks put back together for a chunked body
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// values of the headers named name, case insensitive
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// value of the first header named name, case insensitive
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_slice())
+    }
+}
+
+/// why a request was refused, the server answers 400 Bad Request to all of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    RequestLine,
+    /// not HTTP/1.0 nor HTTP/1.1
+    Version,
+    Header,
+    ContentLength,
+    /// a coding other than chunked, or both Transfer-Encoding and Content-Length
+    TransferEncoding,
+    Chunk,
+    /// a line, the number of headers or the body is over its limit
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Error::RequestLine => "malformed request line",
+            Error::Version => "unsupported HTTP version",
+            Error::Header => "malformed header",
+            Error::ContentLength => "invalid Content-Length",
+            Error::TransferEncoding => "unsupported Transfer-Encoding",
+            Error::Chunk => "malformed chunk",
+            Error::TooLarge => "request too large",
+        })
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// what the parser expects next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    RequestLine,
+    Headers,
+    /// bytes of the body left
+    Body(usize),
+    ChunkSize,
+    /// bytes of the chunk left
+    ChunkData(usize),
+    /// the CRLF after the data of a chunk
+    ChunkEnd,
+    Trailers,
+    /// the input was malformed, the rest of it is never looked at
+    Failed(Error),
+}
+
+/// parser of the requests of one connection, fed with the bytes of every read
+#[derive(Debug)]
+pub struct Parser {
+    buf: Vec<u8>,
+    /// bytes of buf already parsed
+    pos: usize,
+    state: State,
+    request: Request,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Parser { buf: Vec::new(), pos: 0, state: State::RequestLine, request: Request::default() }
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds the bytes of a read, returns the request once it is complete. the bytes that follow it
+    /// are kept for the next request, feed(&[]) parses it. once an error is returned the parser
+    /// keeps returning it
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<Option<Request>, Error> {
+        if let State::Failed(e) = self.state {
+            return Err(e);
+        }
+        self.buf.extend_from_slice(bytes);
+        let parsed = self.parse();
+        match parsed {
+            Err(e) => {
+                self.state = State::Failed(e);
+                self.buf = Vec::new();
+            }
+            Ok(_) => {
+                self.buf.drain(..self.pos);
+            }
+        }
+        self.pos = 0;
+        parsed
+    }
+
+    fn parse(&mut self) -> Result<Option<Request>, Error> {
+        loop {
+            match self.state {
+                State::RequestLine => {
+                    let line = match self.line()? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    // empty lines before a request are ignored
+                    if !line.is_empty() {
+                        let (method, target, minor_version) = request_line(&self.buf[line])?;
+                        self.request.method = method;
+                        self.request.target = target;
+                        self.request.minor_version = minor_version;
+                        self.state = State::Headers;
+                    }
+                }
+                State::Headers => {
+                    let line = match self.line()? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    if line.is_empty() {
+                        self.state = self.body()?;
+                    } else {
+                        if self.request.headers.len() == MAX_HEADERS {
+                            return Err(Error::TooLarge);
+                        }
+                        let header = header(&self.buf[line])?;
+                        self.request.headers.push(header);
+                    }
+                }
+                State::Body(0) => return Ok(Some(self.finish())),
+                State::Body(left) => match self.take(left) {
+                    0 => return Ok(None),
+                    n => self.state = State::Body(left - n),
+                },
+                State::ChunkSize => {
+                    let line = match self.line()? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    let size = chunk_size(&self.buf[line])?;
+                    if size == 0 {
+                        self.state = State::Trailers;
+                    } else if size > MAX_BODY - self.request.body.len() {
+                        return Err(Error::TooLarge);
+                    } else {
+                        self.state = State::ChunkData(size);
+                    }
+                }
+                State::ChunkData(0) => self.state = State::ChunkEnd,
+                State::ChunkData(left) => match self.take(left) {
+                    0 => return Ok(None),
+                    n => self.state = State::ChunkData(left - n),
+                },
+                State::ChunkEnd => match self.line()? {
+                    Some(line) if line.is_empty() => self.state = State::ChunkSize,
+                    Some(_) => return Err(Error::Chunk),
+                    None => return Ok(None),
+                },
+                State::Trailers => match self.line()? {
+                    Some(line) if line.is_empty() => return Ok(Some(self.finish())),
+                    // the trailer fields are checked but not kept
+                    Some(line) => {
+                        header(&self.buf[line])?;
+                    }
+                    None => return Ok(None),
+                },
+                State::Failed(e) => return Err(e),
+            }
+        }
+    }
+
+    /// range of buf of the next line without its CRLF, None until it is complete. a bare LF also ends a line
+    fn line(&mut self) -> Result<Option<Range<usize>>, Error> {
+        let rest = &self.buf[self.pos..];
+        let end = match rest.iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None if rest.len() > MAX_LINE => return Err(Error::TooLarge),
+            None => return Ok(None),
+        };
+        if end > MAX_LINE {
+            return Err(Error::TooLarge);
+        }
+        let start = self.pos;
+        self.pos += end + 1;
+        let cr = end > 0 && rest[end - 1] == b'\r';
+        Ok(Some(start..start + end - cr as usize))
+    }
+
+    /// appends at most left bytes of buf to the body, returns how many
+    fn take(&mut self, left: usize) -> usize {
+        let n = left.min(self.buf.len() - self.pos);
+        self.request.body.extend_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        n
+    }
+
+    /// how the body is delimited, once the headers are read
+    fn body(&self) -> Result<State, Error> {
+        let request = &self.request;
+        if request.header("transfer-encoding").is_some() {
+            if request.header("content-length").is_some() {
+                return Err(Error::TransferEncoding);
+            }
+            // chunked is the only coding known here, and it must be the last one
+            let mut codings = request
+                .header_values("transfer-encoding")
+                .flat_map(|v| v.split(|&b| b == b','))
+                .map(trim)
+                .filter(|coding| !coding.is_empty());
+            return match (codings.next(), codings.next()) {
+                (Some(coding), None) if coding.eq_ignore_ascii_case(b"chunked") => Ok(State::ChunkSize),
+                _ => Err(Error::TransferEncoding),
+            };
+        }
+        // the same length may be repeated, in several headers or as a list
+        let mut length = None;
+        for value in request.header_values("content-length").flat_map(|v| v.split(|&b| b == b',')) {
+            let value = decimal(trim(value))?;
+            if length.is_some_and(|length| length != value) {
+                return Err(Error::ContentLength);
+            }
+            length = Some(value);
+        }
+        match length {
+            Some(length) if length > MAX_BODY => Err(Error::TooLarge),
+            Some(length) => Ok(State::Body(length)),
+            None => Ok(State::Body(0)),
+        }
+    }
+
+    /// the request is over, the parser waits for the next one
+    fn finish(&mut self) -> Request {
+        self.state = State::RequestLine;
+        mem::take(&mut self.request)
+    }
+}
+
+/// method SP request-target SP HTTP-version
+fn request_line(line: &[u8]) -> Result<(String, String, u8), Error> {
+    let mut parts = line.split(|&b| b == b' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(Error::RequestLine),
+    };
+    if method.is_empty() || !method.iter().all(|&b| is_tchar(b)) {
+        return Err(Error::RequestLine);
+    }
+    if target.is_empty() || !target.iter().all(|&b| b.is_ascii_graphic()) {
+        return Err(Error::RequestLine);
+    }
+    let minor_version = match version {
+        b"HTTP/1.1" => 1,
+        b"HTTP/1.0" => 0,
+        [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            return Err(Error::Version)
+        }
+        _ => return Err(Error::RequestLine),
+    };
+    // both are ASCII, each byte is a char
+    Ok((method.iter().map(|&b| b as char).collect(), target.iter().map(|&b| b as char).collect(), minor_version))
+}
+
+/// name ":" OWS value OWS
+fn header(line: &[u8]) -> Result<(String, Vec<u8>), Error> {
+    let colon = line.iter().position(|&b| b == b':').ok_or(Error::Header)?;
+    let (name, value) = (&line[..colon], trim(&line[colon + 1..]));
+    // no whitespace before the colon, which also refuses the obsolete line folding
+    if name.is_empty() || !name.iter().all(|&b| is_tchar(b)) {
+        return Err(Error::Header);
+    }
+    // visible characters, spaces, tabs and bytes above 0x7f, no control character
+    if !value.iter().all(|&b| b == b'\t' || b == b' ' || b.is_ascii_graphic() || b >= 0x80) {
+        return Err(Error::Header);
+    }
+    Ok((name.iter().map(|&b| b.to_ascii_lowercase() as char).collect(), value.to_vec()))
+}
+
+/// hexadecimal size, followed by extensions that are ignored
+fn chunk_size(line: &[u8]) -> Result<usize, Error> {
+    let size = trim(line.split(|&b| b == b';').next().unwrap_or(line));
+    if size.is_empty() {
+        return Err(Error::Chunk);
+    }
+    size.iter().try_fold(0usize, |n, &b| {
+        let digit = (b as char).to_digit(16).ok_or(Error::Chunk)?;
+        n.checked_mul(16)
+            .and_then(|n| n.checked_add(digit as usize))
+            .ok_or(Error::TooLarge)
+    })
+}
+
+fn decimal(value: &[u8]) -> Result<usize, Error> {
+    if value.is_empty() {
+        return Err(Error::ContentLength);
+    }
+    value.iter().try_fold(0usize, |n, &b| {
+        if !b.is_ascii_digit() {
+            return Err(Error::ContentLength);
+        }
+        n.checked_mul(10)
+            .and_then(|n| n.checked_add((b - b'0') as usize))
+            .ok_or(Error::TooLarge)
+    })
+}
+
+/// without the spaces and tabs around it
+fn trim(bytes: &[u8]) -> &[u8] {
+    let is_space = |b: &u8| *b == b' ' || *b == b'\t';
+    let start = bytes.iter().position(|b| !is_space(b)).unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(|b| !is_space(b)).map_or(start, |end| end + 1);
+    &bytes[start..end]
+}
+
+/// characters of a method or a header name
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Result<Option<Request>, Error> {
+        Parser::new().feed(bytes)
+    }
+
+    #[test]
+    fn get() {
+        let request = parse(b"GET /index.html HTTP/1.1\r\nHost: localhost:8000\r\nAccept:  */* \r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.target, "/index.html");
+        assert_eq!(request.minor_version, 1);
+        assert_eq!(request.header("host"), Some(&b"localhost:8000"[..]));
+        assert_eq!(request.header("ACCEPT"), Some(&b"*/*"[..]));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let bytes = b"\r\nPOST /form HTTP/1.0\nContent-Length: 11\n\nhello world";
+        let mut parser = Parser::new();
+        for &b in &bytes[..bytes.len() - 1] {
+            assert_eq!(parser.feed(&[b]), Ok(None));
+        }
+        let request = parser.feed(&bytes[bytes.len() - 1..]).unwrap().unwrap();
+        assert_eq!((request.method.as_str(), request.minor_version), ("POST", 0));
+        assert_eq!(request.body, b"hello world");
+    }
+
+    #[test]
+    fn chunked() {
+        let mut parser = Parser::new();
+        let bytes: &[u8] = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                             5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n";
+        for part in bytes.chunks(7) {
+            if let Some(request) = parser.feed(part).unwrap() {
+                assert_eq!(request.body, b"hello world");
+                assert_eq!(request.header("expires"), None);
+                return;
+            }
+        }
+        panic!("the request never ended");
+    }
+
+    #[test]
+    fn pipelined() {
+        let mut parser = Parser::new();
+        let first = parser
+            .feed(b"GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 3, 3\r\ncontent-length: 3\r\n\r\nabc")
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.target, "/a");
+        let second = parser.feed(&[]).unwrap().unwrap();
+        assert_eq!((second.target.as_str(), second.body.as_slice()), ("/b", &b"abc"[..]));
+        assert_eq!(parser.feed(&[]), Ok(None));
+    }
+
+    #[test]
+    fn malformed() {
+        let cases: &[(&[u8], Error)] = &[
+            (b"GET\r\n\r\n", Error::RequestLine),
+            (b"GET  / HTTP/1.1\r\n\r\n", Error::RequestLine),
+            (b"GET /\xff\xfe HTTP/1.1\r\n\r\n", Error::RequestLine),
+            (b"G(T / HTTP/1.1\r\n\r\n", Error::RequestLine),
+            (b"GET / HTTP/2.0\r\n\r\n", Error::Version),
+            (b"GET / HTTX/1.1\r\n\r\n", Error::RequestLine),
+            (b"GET / HTTP/1.1\r\nHost localhost\r\n\r\n", Error::Header),
+            (b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n", Error::Header),
+            (b"GET / HTTP/1.1\r\nA: b\r\n folded\r\n\r\n", Error::Header),
+            (b"GET / HTTP/1.1\r\nA: b\x00c\r\n\r\n", Error::Header),
+            (b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n", Error::ContentLength),
+            (b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n", Error::ContentLength),
+            (b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n", Error::TooLarge),
+            (b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n", Error::TransferEncoding),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n", Error::TransferEncoding),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n", Error::Chunk),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n", Error::Chunk),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n200000\r\n", Error::TooLarge),
+        ];
+        for (bytes, error) in cases {
+            assert_eq!(parse(bytes), Err(*error), "{}", String::from_utf8_lossy(bytes));
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.feed(&[b'a'; MAX_LINE]), Ok(None));
+        assert_eq!(parser.feed(b"a"), Err(Error::TooLarge));
+        // the parser stays failed
+        assert_eq!(parser.feed(b"GET / HTTP/1.1\r\n\r\n"), Err(Error::TooLarge));
+        let mut headers = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..=MAX_HEADERS {
+            headers.extend_from_slice(format!("x-{}: {}\r\n", i, i).as_bytes());
+        }
+        assert_eq!(parse(&headers), Err(Error::TooLarge));
+    }
+}
@@ -0,0 +1,2 @@
+// le serveur epoll et ce qu'il utilise
+pub mod http;
@@ -1,6 +1,8 @@
+use epoll_server::http::Parser;
 use rand::Rng;
 use std::collections::HashMap;
 use std::io::prelude::*;
+use std::io::ErrorKind;
 use std::net::{TcpListener, TcpStream};
 use std::os::unix::io::AsRawFd;
 
@@ -12,15 +14,47 @@ content-length: 5
 
 Hello";
 
+const HTTP_BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+#[derive(Clone, Copy)]
 enum Action {
     Reading,
-    Writing,
+    // the response to write once the stream is writable, and how many of its bytes were written
+    Writing(&'static [u8], usize),
+    // the response was written and our side shut, we read what the client still sends until it
+    // closes, so that unread bytes do not make the kernel reset the connection
+    Draining,
+}
+
+// We remove the stream from the interest list of our epoll instance and forget this connection,
+// dropping the TcpStream closes its file descriptor
+fn close_connection(
+    epoll_file_descriptor: i32,
+    key: u64,
+    map_key_stream: &mut HashMap<u64, TcpStream>,
+    map_key_parser: &mut HashMap<u64, Parser>,
+    map_key_action: &mut HashMap<u64, Action>,
+) {
+    if let Some(stream) = map_key_stream.remove(&key) {
+        unsafe {
+            libc::epoll_ctl(
+                epoll_file_descriptor,
+                libc::EPOLL_CTL_DEL,
+                stream.as_raw_fd(),
+                std::ptr::null_mut(),
+            )
+        };
+    }
+    map_key_parser.remove(&key);
+    map_key_action.remove(&key);
 }
 
 fn main() -> std::io::Result<()> {
     let mut rng = rand::thread_rng();
     let mut map_key_stream: HashMap<u64, TcpStream> = HashMap::new();
     let mut map_key_action: HashMap<u64, Action> = HashMap::new();
+    // Each connection has its own parser, which keeps what was read until the request is complete
+    let mut map_key_parser: HashMap<u64, Parser> = HashMap::new();
 
     // We create a file descriptor associated to a TcpListener
     let listener = TcpListener::bind("127.0.0.1:8000")?;
@@ -67,6 +101,7 @@ fn main() -> std::io::Result<()> {
                             // We remember the mapping between the generated key and this TcpStream
                             map_key_stream.insert(key, stream);
                             map_key_action.insert(key, Action::Reading);
+                            map_key_parser.insert(key, Parser::new());
                             // We add the file descriptor for this stream to the interest list of our epoll instance
                             let mut epoll_event = libc::epoll_event {
                                 events: libc::EPOLLIN as u32,
@@ -88,7 +123,12 @@ fn main() -> std::io::Result<()> {
                 }
                 // A TcpStream is ready for I/O
                 key => {
-                    match map_key_action.get(&key).unwrap() {
+                    // The connection may have been closed by an earlier event of this batch
+                    let action = match map_key_action.get(&key) {
+                        Some(&action) => action,
+                        None => continue,
+                    };
+                    match action {
                         Action::Reading => {
                             // note :  here we can see the level trigger behaviour as if
                             // we set buf small enough, for example [0u8; 256] so that one 
@@ -102,14 +142,82 @@ fn main() -> std::io::Result<()> {
                             let mut stream = map_key_stream.get(&key).unwrap();
                             // We read its content
                             let mut buf = [0u8; 256];
-                            stream.read(&mut buf)?;
-                            let content = std::str::from_utf8(&buf).unwrap();
-                            //println!("content read : {:?}", content);
-                            // we check if we are done reading (dumb! do not reproduce)
-                            if content.contains("\r\n\r\n") {
-                                // we change the event from reading to writing for this TcpStream
+                            let read = match stream.read(&mut buf) {
+                                Ok(read) => read,
+                                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => continue,
+                                Err(_) => 0,
+                            };
+                            if read == 0 {
+                                // The client left before sending a whole request
+                                close_connection(
+                                    epoll_file_descriptor,
+                                    key,
+                                    &mut map_key_stream,
+                                    &mut map_key_parser,
+                                    &mut map_key_action,
+                                );
+                                continue;
+                            }
+                            // The parser adds what we read to the bytes of the previous reads, we are
+                            // done reading once it has a whole request or knows it is malformed
+                            let response = match map_key_parser.get_mut(&key).unwrap().feed(&buf[..read]) {
+                                Ok(None) => continue,
+                                Ok(Some(_request)) => HTTP_RESP,
+                                Err(_) => HTTP_BAD_REQUEST,
+                            };
+                            // we change the event from reading to writing for this TcpStream
+                            let mut epoll_event = libc::epoll_event {
+                                events: libc::EPOLLOUT as u32,
+                                u64: key,
+                            };
+                            unsafe {
+                                libc::epoll_ctl(
+                                    epoll_file_descriptor,
+                                    libc::EPOLL_CTL_MOD,
+                                    stream.as_raw_fd(),
+                                    &mut epoll_event,
+                                )
+                            };
+                            map_key_action.insert(key, Action::Writing(response, 0));
+                        }
+                        Action::Writing(response, mut written) => {
+                            // We get the TcpStream associated to this key
+                            let mut stream = map_key_stream.get(&key).unwrap();
+                            // The socket is nonblocking, a write may take only part of the response
+                            let mut gone = false;
+                            while written < response.len() {
+                                match stream.write(&response[written..]) {
+                                    Ok(0) => {
+                                        gone = true;
+                                        break;
+                                    }
+                                    Ok(n) => written += n,
+                                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                                    Err(_) => {
+                                        gone = true;
+                                        break;
+                                    }
+                                }
+                            }
+                            if gone {
+                                // The client is gone, there is no one left to answer
+                                close_connection(
+                                    epoll_file_descriptor,
+                                    key,
+                                    &mut map_key_stream,
+                                    &mut map_key_parser,
+                                    &mut map_key_action,
+                                );
+                            } else if written < response.len() {
+                                // We go on once the stream is writable again
+                                map_key_action.insert(key, Action::Writing(response, written));
+                            } else {
+                                // The whole response is sent, we tell the client we are done writing
+                                // and wait for it to close its side
+                                let _ = stream.shutdown(std::net::Shutdown::Write);
                                 let mut epoll_event = libc::epoll_event {
-                                    events: libc::EPOLLOUT as u32,
+                                    events: libc::EPOLLIN as u32,
                                     u64: key,
                                 };
                                 unsafe {
@@ -120,28 +228,28 @@ fn main() -> std::io::Result<()> {
                                         &mut epoll_event,
                                     )
                                 };
-                                map_key_action.insert(key, Action::Writing);
+                                map_key_action.insert(key, Action::Draining);
                             }
                         }
-                        Action::Writing => {
-                            // We get the TcpStream associated to this key
+                        Action::Draining => {
                             let mut stream = map_key_stream.get(&key).unwrap();
-                            // We write the HTTP response
-                            stream.write(HTTP_RESP).unwrap();
-                            // We close the stream and remove it from the epoll instance
-                            stream.shutdown(std::net::Shutdown::Both).unwrap();
-                            let stream_file_descriptor = stream.as_raw_fd();
-                            unsafe {
-                                libc::epoll_ctl(
-                                    epoll_file_descriptor,
-                                    libc::EPOLL_CTL_DEL,
-                                    stream_file_descriptor,
-                                    std::ptr::null_mut(),
-                                )
-                            };
-                            // We close the file descriptor as there is a limit
-                            // for the number of file descriptors one process can open (1024)
-                            unsafe { libc::close(stream_file_descriptor) };
+                            // What the client still sends is dropped, until it closes its side
+                            let mut buf = [0u8; 256];
+                            match stream.read(&mut buf) {
+                                Ok(0) => {}
+                                Ok(_) => continue,
+                                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => continue,
+                                Err(_) => {}
+                            }
+                            // We close the stream and remove it from the epoll instance, as there is a
+                            // limit for the number of file descriptors one process can open (1024)
+                            close_connection(
+                                epoll_file_descriptor,
+                                key,
+                                &mut map_key_stream,
+                                &mut map_key_parser,
+                                &mut map_key_action,
+                            );
                         }
                     }
                 }